@@ -2,11 +2,16 @@ extern crate futures;
 extern crate http;
 extern crate hyper;
 extern crate hyper_native_tls;
+#[cfg(feature = "opentelemetry")]
+extern crate opentelemetry;
 extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg_attr(any(test, feature = "cli"), macro_use)]
 extern crate serde_json;
+extern crate tokio;
+extern crate tokio_sync;
 
 pub use message::response::GcmError as Error;
 pub use message::*;
@@ -2,21 +2,40 @@ extern crate http;
 extern crate reqwest;
 
 use futures::future::{err, ok};
-use futures::Future;
-use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use futures::{stream, Future, Stream};
+use http::header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
 use http::HeaderMap;
 use reqwest::async::{Client, ClientBuilder, Response};
+use serde_json::Value as JsonValue;
 
 use gcm_util;
+use message::limiter::ConcurrencyLimiter;
 use message::Message;
 use message::response::{GcmError, GcmResponse};
+use message::telemetry;
+use message::validate::MAX_REGISTRATION_IDS;
 
-pub type GcmResponseFuture = Box<Future<Item=GcmResponse, Error=GcmError> + Send>;
+pub type GcmResponseFuture = Box<dyn Future<Item=GcmResponse, Error=GcmError> + Send>;
 
+/// A single request, failing with the error and the `Retry-After` seconds
+/// of the answer, if it had any.
+pub(crate) type AttemptFuture = Box<dyn Future<Item=GcmResponse, Error=(GcmError, Option<u64>)> + Send>;
+
+pub type TokenBatchFuture = Box<dyn Future<Item=Option<Vec<String>>, Error=GcmError> + Send>;
+
+/// A lazily fetched audience, e.g. pages of a database query, for
+/// `AsyncGsmSender::send_from_provider`.
+pub trait TokenProvider {
+    /// The next batch of registration ids, or `None` once there are no more.
+    fn next_batch(&mut self) -> TokenBatchFuture;
+}
+
+#[derive(Clone)]
 pub struct AsyncGsmSender {
     client: Client,
     gcm_url: String,
     ids_by_error: bool,
+    limiter: Option<ConcurrencyLimiter>,
 }
 
 impl AsyncGsmSender {
@@ -42,39 +61,160 @@ impl AsyncGsmSender {
             .default_headers(headers)
             .build()
             .expect("new async client");
-        AsyncGsmSender { client, gcm_url: gcm_url,ids_by_error}
+        AsyncGsmSender { client, gcm_url, ids_by_error, limiter: None }
+    }
+
+    /// Wait for `limiter` before every request, bounding how many are in flight
+    /// at once. Clones of the sender, and any other sender given a clone of the
+    /// same limiter, share the bound, which then holds for the whole process
+    /// however many tasks send.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::async_sender::AsyncGsmSender;
+    /// use gcm::limiter::ConcurrencyLimiter;
+    ///
+    /// let limiter = ConcurrencyLimiter::new(16);
+    /// let sender = AsyncGsmSender::new("<api-key>".to_string(), "https://fcm.googleapis.com/fcm/send".to_string(), false)
+    ///     .with_shared_limiter(limiter.clone());
+    /// let for_other_task = sender.clone();
+    /// ```
+    pub fn with_shared_limiter(mut self, limiter: ConcurrencyLimiter) -> AsyncGsmSender {
+        self.limiter = Some(limiter);
+        self
     }
 
     pub fn send(&self, msg: Message) -> GcmResponseFuture {
-        let result = gcm_util::to_json(&msg);
-        let reg_ids = msg.registration_ids;
-        let should_build_error_map = self.ids_by_error;
+        let body = match gcm_util::to_json(&msg) {
+            Ok(body) => body,
+            Err(e) => return Box::new(err(e)),
+        };
+        // results of a single token send belong to that token, topics and
+        // conditions have no ids to match them to
+        let reg_ids = match msg.registration_ids {
+            Some(ref ids) => ids.clone(),
+            None if msg.to_is_topic() => Vec::new(),
+            None => msg.to.map(|to| vec![to.to_string()]).unwrap_or_default(),
+        };
+        let send = Box::new(self.limited_post(body, reg_ids).map_err(|(error, _)| error));
+        telemetry::traced_future(&msg, send)
+    }
+
+    /// Send `msg` to every registration id `provider` yields, pulling one
+    /// batch at a time and only asking for the next once the previous one is
+    /// sent, so the audience is never held in memory as a whole. Batches over
+    /// 1000 ids go out as several requests. The recipients of `msg` itself
+    /// are ignored. Fails on the first error, of the provider or of a send.
+    /// # Examples:
+    /// ```rust
+    /// extern crate futures;
+    /// extern crate gcm;
+    ///
+    /// use futures::future;
+    /// use gcm::async_sender::{AsyncGsmSender, TokenBatchFuture, TokenProvider};
+    /// use gcm::Message;
+    ///
+    /// struct Pages(Vec<Vec<String>>);
+    ///
+    /// impl TokenProvider for Pages {
+    ///     fn next_batch(&mut self) -> TokenBatchFuture {
+    ///         Box::new(future::ok(self.0.pop()))
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// let sender = AsyncGsmSender::new("<api-key>".to_string(), "https://fcm.googleapis.com/fcm/send".to_string(), false);
+    /// let pages = Pages(vec![vec!["<registration id>".to_string()]]);
+    /// let responses = sender.send_from_provider(Message::new(vec![]).collapse_key("scores"), pages);
+    /// # }
+    /// ```
+    pub fn send_from_provider<P>(&self, msg: Message, provider: P) -> Box<dyn Future<Item=Vec<GcmResponse>, Error=GcmError> + Send>
+    where
+        P: TokenProvider + Send + 'static,
+    {
+        let base = match template_body(&msg) {
+            Ok(base) => base,
+            Err(e) => return Box::new(err(e)),
+        };
 
-        match result {
-            Err(e) => Box::new(err(e)),
-            Ok(body) => {
-                let and_then = self
-                    .client
-                    .post(&self.gcm_url)
-                    .body(body)
-                    .send()
-                    .map_err(|err| gcm_util::parse_error_status_code(err.status()))
-                    .and_then(move |res| AsyncGsmSender::parse(res, reg_ids.unwrap(), should_build_error_map));
-                Box::new(and_then)
+        // the provider is dropped once it runs dry, which ends the stream
+        let batches = stream::unfold(Some(provider), |provider| {
+            provider.map(|mut provider| {
+                provider.next_batch().map(move |batch| {
+                    let provider = if batch.is_some() { Some(provider) } else { None };
+                    (batch, provider)
+                })
+            })
+        });
+
+        let sender = self.clone();
+        let responses = batches
+            .filter_map(|batch| batch)
+            .map(|batch| {
+                let chunks: Vec<Vec<String>> = batch
+                    .chunks(MAX_REGISTRATION_IDS)
+                    .map(|chunk| chunk.to_vec())
+                    .collect();
+                stream::iter_ok(chunks)
+            })
+            .flatten()
+            .and_then(move |reg_ids| {
+                let mut body = base.clone();
+                body["registration_ids"] = reg_ids.clone().into();
+                let token_count = reg_ids.len();
+                let send = Box::new(sender.limited_post(body.to_string(), reg_ids).map_err(|(error, _)| error));
+                telemetry::traced_batch(token_count, send)
+            })
+            .collect();
+        Box::new(responses)
+    }
+
+    pub(crate) fn limited_post(&self, body: String, reg_ids: Vec<String>) -> AttemptFuture {
+        match self.limiter {
+            None => self.post(body, reg_ids),
+            Some(ref limiter) => {
+                let sender = self.clone();
+                let limited = limiter
+                    .acquire()
+                    .map_err(|error| (error, None))
+                    .and_then(move |permit| {
+                        sender.post(body, reg_ids).then(move |result| {
+                            drop(permit);
+                            result
+                        })
+                    });
+                Box::new(limited)
             }
         }
     }
 
-    fn parse(mut res: Response, ids: Vec<String>,should_build_error_map : bool) -> GcmResponseFuture {
+    fn post(&self, body: String, reg_ids: Vec<String>) -> AttemptFuture {
+        let should_build_error_map = self.ids_by_error;
+        let and_then = self
+            .client
+            .post(&self.gcm_url)
+            .body(body)
+            .send()
+            .map_err(|err| (gcm_util::parse_error_status_code(err.status()), None))
+            .and_then(move |res| AsyncGsmSender::parse(res, reg_ids, should_build_error_map));
+        Box::new(and_then)
+    }
+
+    fn parse(mut res: Response, ids: Vec<String>,should_build_error_map : bool) -> AttemptFuture {
         let status_code = res.status().as_u16();
+        // only the delay-seconds form of Retry-After is understood
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok());
 
         match status_code {
             200 => {
                 let then = res
                     .json::<GcmResponse>()
-                    .map_err(|_| GcmError::InvalidJsonBody)
+                    .map_err(|_| (GcmError::InvalidJsonBody, None))
                     .and_then(move |mut gcm_resp| {
-                        if should_build_error_map && gcm_resp.results.is_some() {
+                        if should_build_error_map && !ids.is_empty() && gcm_resp.results.is_some() {
                             gcm_resp.build_reg_ids_by_error_map(ids);
                         }
                         ok(gcm_resp)
@@ -82,9 +222,29 @@ impl AsyncGsmSender {
 
                 Box::new(then)
             }
-            _ => Box::new(err(gcm_util::parse_error_status_code(Some(res.status())))),
+            // a 400 may say why in its body, like for `GcmSender`
+            _ if res.status().is_client_error() => {
+                let then = res.text().then(move |body| {
+                    let body = body.unwrap_or_default();
+                    err::<GcmResponse, _>((gcm_util::status_error(status_code, &body), retry_after))
+                });
+                Box::new(then)
+            }
+            _ => Box::new(err((gcm_util::parse_error_status_code(Some(res.status())), retry_after))),
         }
     }
 
 
 }
+
+/// The body of `msg` without its recipients, to be sent to any batch of ids
+/// by setting `registration_ids`.
+pub(crate) fn template_body(msg: &Message) -> Result<JsonValue, GcmError> {
+    let mut body = gcm_util::to_json_value(msg)?;
+    if let Some(fields) = body.as_object_mut() {
+        for key in &["to", "condition", "registration_ids"] {
+            fields.remove(*key);
+        }
+    }
+    Ok(body)
+}
@@ -0,0 +1,69 @@
+//! Sending from the shell: read messages as JSON lines, send each, report
+//! each outcome as a JSON line. Built with the `cli` feature.
+//!
+//! Every input line is one message in the legacy GCM format, e.g.
+//! `{"registration_ids": ["<registration id>"], "data": {"sync": "1"}}`.
+//! Blank lines are skipped. A line that cannot be parsed or sent is reported
+//! and the run goes on with the next one. Outcome lines look like
+//!
+//! ```text
+//! {"line":1,"status":"sent","response":{"multicast_id":7,"success":1,...}}
+//! {"line":2,"status":"failed","error":"UnauthorizedError"}
+//! {"line":3,"status":"invalid","error":"expected value at line 1 column 1"}
+//! ```
+
+use std::io::{self, BufRead, Write};
+
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use message::sender::GcmSender;
+use message::Message;
+
+/// Send every message read from `reader`, printing the outcomes to stdout.
+/// # Examples:
+/// ```rust,no_run
+/// use std::io;
+/// use gcm::cli;
+/// use gcm::sender::GcmSender;
+///
+/// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+/// let stdin = io::stdin();
+/// cli::run_send_from_reader(stdin.lock(), &sender).unwrap();
+/// ```
+pub fn run_send_from_reader<R: BufRead>(reader: R, sender: &GcmSender) -> io::Result<()> {
+    let stdout = io::stdout();
+    run_send_to_writer(reader, sender, stdout.lock())
+}
+
+/// `run_send_from_reader` writing the outcomes to `writer`. Fails only when
+/// reading or writing does.
+pub fn run_send_to_writer<R: BufRead, W: Write>(
+    reader: R,
+    sender: &GcmSender,
+    mut writer: W,
+) -> io::Result<()> {
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // through a `Value`, so escaped strings can be borrowed by the message
+        let outcome = match serde_json::from_str::<JsonValue>(&line) {
+            Err(e) => json!({"line": i + 1, "status": "invalid", "error": e.to_string()}),
+            Ok(value) => match Message::deserialize(&value) {
+                Err(e) => json!({"line": i + 1, "status": "invalid", "error": e.to_string()}),
+                Ok(message) => match sender.send(message) {
+                    Ok(response) => json!({"line": i + 1, "status": "sent", "response": response}),
+                    Err(e) => json!({"line": i + 1, "status": "failed", "error": e.to_string()}),
+                },
+            },
+        };
+
+        writeln!(writer, "{}", outcome)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use message::response::{GcmError, GcmResponse, MessageResult};
+use message::sender::GcmSender;
+use message::transport::SendTransport;
+use message::Message;
+
+/// What became of a single recipient in one of the compared responses.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Classification {
+    Delivered,
+    /// Delivered, GCM asks to replace the registration id with this one.
+    Canonical(u64),
+    /// Failed with the given error, e.g. `NotRegistered`.
+    Failed(String),
+    /// The response has no result for the recipient.
+    Missing,
+    /// The whole request failed.
+    RequestFailed(GcmError),
+}
+
+/// A recipient the two transports disagree on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    /// Registration id, or the `to`/`condition` of the message.
+    pub recipient: String,
+    pub primary: Classification,
+    pub candidate: Classification,
+}
+
+/// The outcome of `ComparisonSender::compare`.
+#[derive(Debug)]
+pub struct Comparison {
+    pub primary: Result<GcmResponse, GcmError>,
+    pub candidate: Result<GcmResponse, GcmError>,
+    /// Recipients classified differently, in the order they were sent.
+    pub differences: Vec<Difference>,
+}
+
+impl Comparison {
+    /// Whether both transports classified every recipient the same way.
+    pub fn is_equivalent(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// A migration aid: dry runs every message through two transports, e.g. the
+/// legacy endpoint and a v1 adapter, and reports the recipients whose
+/// outcome differs. Both transports get the same legacy body, a transport
+/// for another API translates it on its own. Nothing is delivered.
+/// # Examples:
+/// ```rust,no_run
+/// use gcm::Message;
+/// use gcm::comparison::ComparisonSender;
+/// use gcm::transport::HttpTransport;
+///
+/// let legacy = HttpTransport::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+/// let proxy = HttpTransport::new("https://push-proxy.example.com/send".to_string(), "<api-key>".to_string());
+/// let sender = ComparisonSender::new(Box::new(legacy), Box::new(proxy));
+///
+/// let comparison = sender.compare(Message::new(vec!["<registration id>"]));
+/// for difference in &comparison.differences {
+///     println!("{}: {:?} vs {:?}", difference.recipient, difference.primary, difference.candidate);
+/// }
+/// ```
+pub struct ComparisonSender {
+    primary: GcmSender,
+    candidate: GcmSender,
+}
+
+impl ComparisonSender {
+    pub fn new(primary: Box<dyn SendTransport>, candidate: Box<dyn SendTransport>) -> ComparisonSender {
+        ComparisonSender {
+            primary: GcmSender::with_transport(primary),
+            candidate: GcmSender::with_transport(candidate),
+        }
+    }
+
+    pub fn compare(&self, msg: Message) -> Comparison {
+        let recipients: Vec<String> = match (msg.to, msg.condition, &msg.registration_ids) {
+            (Some(to), _, _) => vec![to.to_string()],
+            (_, Some(condition), _) => vec![condition.to_string()],
+            (_, _, Some(ids)) => ids.clone(),
+            _ => Vec::new(),
+        };
+
+        let msg = msg.dry_run(true);
+        let primary = self.primary.send(msg.clone());
+        let candidate = self.candidate.send(msg);
+
+        let differences = recipients
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, recipient)| {
+                let primary = classify(&primary, i);
+                let candidate = classify(&candidate, i);
+                if primary == candidate {
+                    None
+                } else {
+                    Some(Difference {
+                        recipient,
+                        primary,
+                        candidate,
+                    })
+                }
+            })
+            .collect();
+
+        Comparison {
+            primary,
+            candidate,
+            differences,
+        }
+    }
+}
+
+fn classify(response: &Result<GcmResponse, GcmError>, index: usize) -> Classification {
+    let response = match *response {
+        Ok(ref response) => response,
+        Err(ref error) => return Classification::RequestFailed(error.clone()),
+    };
+
+    // a topic or condition send answers with a bare message id
+    if index == 0 && response.results.is_none() {
+        return match (response.message_id, &response.error) {
+            (_, Some(error)) => Classification::Failed(error.clone()),
+            (Some(_), _) => Classification::Delivered,
+            _ => Classification::Missing,
+        };
+    }
+
+    match response.results.as_ref().and_then(|results| results.get(index)) {
+        Some(&MessageResult {
+            error: Some(ref error),
+            ..
+        }) => Classification::Failed(error.clone()),
+        Some(&MessageResult {
+            registration_id: Some(id),
+            ..
+        }) => Classification::Canonical(id),
+        Some(_) => Classification::Delivered,
+        None => Classification::Missing,
+    }
+}
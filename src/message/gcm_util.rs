@@ -1,17 +1,140 @@
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use http::StatusCode as HttpStatusCode;
 use hyper::status::StatusClass;
 use hyper::status::StatusCode;
 
 use message::response::GcmError;
+use message::validate::MAX_REGISTRATION_IDS;
 use message::Message;
+use serde_json::Value;
+
+/// Upper bound for the exponential backoff between retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 pub fn to_json(msg: &Message) -> Result<String, GcmError> {
-    match serde_json::to_string(msg) {
+    match msg.to_json_string() {
         Ok(parsed_json) => Ok(parsed_json),
         Err(_) => Err(GcmError::InvalidJsonBody),
     }
 }
 
+pub fn to_json_value(msg: &Message) -> Result<Value, GcmError> {
+    serde_json::to_value(msg).map_err(|_| GcmError::InvalidJsonBody)
+}
+
+/// The most registration ids of `token_len` bytes that can go with the
+/// settings of `sample_message` without the serialized request growing past
+/// `max_body_bytes`, capped at the 1000 GCM accepts. 0 if not even one fits.
+/// The recipients of `sample_message` do not count, only its other fields.
+/// # Examples:
+/// ```rust
+/// use gcm::{gcm_util, Message};
+///
+/// let sample = Message::new(vec![]).collapse_key("scores");
+///
+/// assert_eq!(gcm_util::optimal_batch_size(&sample, 152, 64 * 1024), 422);
+/// assert_eq!(gcm_util::optimal_batch_size(&sample, 152, 1024 * 1024), 1000);
+/// ```
+pub fn optimal_batch_size(sample_message: &Message, token_len: usize, max_body_bytes: usize) -> usize {
+    let mut empty = sample_message.clone();
+    empty.to = None;
+    empty.condition = None;
+    empty.registration_ids = Some(Vec::new());
+    let base = match empty.to_json_string() {
+        Ok(body) => body.len(),
+        Err(_) => return 0,
+    };
+
+    // every token is quoted, all but the first come after a comma
+    let per_token = token_len + 3;
+    if max_body_bytes + 1 < base + per_token {
+        return 0;
+    }
+    ((max_body_bytes + 1 - base) / per_token).min(MAX_REGISTRATION_IDS)
+}
+
+/// A 64 bit FNV-1a hash of a serialized message body. Stable across
+/// processes and releases, unlike `std`'s `DefaultHasher`, so hashes logged by
+/// different instances can be compared.
+/// # Examples:
+/// ```rust
+/// use gcm::gcm_util;
+///
+/// let body = r#"{"registration_ids":["a"]}"#;
+/// assert_eq!(gcm_util::payload_hash(body), gcm_util::payload_hash(body));
+/// assert_ne!(gcm_util::payload_hash(body), gcm_util::payload_hash(r#"{"registration_ids":["b"]}"#));
+/// ```
+pub fn payload_hash(body: &str) -> u64 {
+    fnv1a(body.as_bytes())
+}
+
+/// Get a new id to correlate the requests of one logical send. Ids are unique
+/// within the process and very unlikely to repeat across processes.
+pub fn new_operation_id() -> String {
+    format!("{:016x}", unique_seed())
+}
+
+/// The wait before the `attempt`-th retry: what `Retry-After` asked for if
+/// the answer had one, else `base_delay * 2^(attempt-1)` capped at a minute.
+pub(crate) fn retry_delay(
+    base_delay: Duration,
+    attempt: u32,
+    retry_after: Option<u64>,
+) -> Duration {
+    if let Some(seconds) = retry_after {
+        return Duration::from_secs(seconds);
+    }
+
+    2u32.checked_pow(attempt - 1)
+        .and_then(|factor| base_delay.checked_mul(factor))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+/// A value that differs on every call, within and across processes.
+pub(crate) fn unique_seed() -> u64 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0);
+    let seed = format!("{}-{}-{}", process::id(), nanos, COUNTER.fetch_add(1, Ordering::SeqCst));
+    fnv1a(seed.as_bytes())
+}
+
+/// Small seedable pseudo random generator (SplitMix64), good enough to pick
+/// samples, not for anything security related.
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> SplitMix64 {
+        SplitMix64(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..bound`, `bound` must not be zero.
+    pub(crate) fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// 64-bit FNV-1a hash, stable across platforms and releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 // Todo : documentation
 pub fn parse_error_status_code(http_status_option: Option<HttpStatusCode>) -> GcmError {
     match http_status_option {
@@ -21,8 +144,36 @@ pub fn parse_error_status_code(http_status_option: Option<HttpStatusCode>) -> Gc
 }
 
 fn parse_error_status(http_status: HttpStatusCode) -> GcmError {
-    let hyper_status_code = StatusCode::from_u16(http_status.as_u16());
+    status_error(http_status.as_u16(), &http_status.to_string())
+}
 
+// the reason given by a JSON error body: `error: error_description` in the
+// legacy format, the `message` of the error object in the v1 one
+fn error_reason(body: &str) -> Option<String> {
+    let parsed: Value = serde_json::from_str(body).ok()?;
+    let error = &parsed["error"];
+    match (error.as_str(), parsed["error_description"].as_str()) {
+        (Some(error), Some(description)) => Some(format!("{}: {}", error, description)),
+        (Some(error), None) => Some(error.to_string()),
+        (None, Some(description)) => Some(description.to_string()),
+        (None, None) => error["message"].as_str().map(str::to_string),
+    }
+}
+
+/// Map a non-200 answer of GCM to the matching error. `body` is only kept for
+/// bad requests: a JSON body giving a reason becomes `InvalidRequest` with
+/// that reason, as `error: error_description` when there is a description;
+/// anything else is kept as it is in `InvalidMessage`.
+pub fn status_error(status: u16, body: &str) -> GcmError {
+    let hyper_status_code = StatusCode::from_u16(status);
+
+    //gateway errors come from intermediaries rather than GCM itself
+    match hyper_status_code {
+        StatusCode::BadGateway | StatusCode::ServiceUnavailable | StatusCode::GatewayTimeout => {
+            return GcmError::Gateway { status }
+        }
+        _ => {}
+    }
     //check for server error (5xx)
     if hyper_status_code.class() == StatusClass::ServerError {
         return GcmError::ServerError;
@@ -30,7 +181,10 @@ fn parse_error_status(http_status: HttpStatusCode) -> GcmError {
     //match remaining status codes
     match hyper_status_code {
         StatusCode::Unauthorized => GcmError::Unauthorized,
-        StatusCode::BadRequest => GcmError::InvalidMessage(http_status.to_string()),
+        StatusCode::BadRequest => match error_reason(body) {
+            Some(reason) => GcmError::InvalidRequest(reason),
+            None => GcmError::InvalidMessage(body.to_string()),
+        },
         _ => GcmError::InvalidMessage("Unknown Error".to_string()),
     }
 }
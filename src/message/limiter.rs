@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use futures::future;
+use futures::{Async, Future};
+use tokio_sync::semaphore::{Permit, Semaphore};
+
+use message::response::GcmError;
+
+/// A bound on the number of requests in flight, shared by every sender it is
+/// installed on (see `AsyncGsmSender::with_shared_limiter`). Clones share the
+/// same bound.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+    max_in_flight: usize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_in_flight: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            semaphore: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight,
+        }
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// How many more requests may start right now.
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// Wait for a free slot, held until the returned permit is dropped.
+    pub(crate) fn acquire(&self) -> impl Future<Item = LimiterPermit, Error = GcmError> + Send {
+        let mut pending = Some(LimiterPermit {
+            semaphore: self.semaphore.clone(),
+            permit: Permit::new(),
+        });
+
+        future::poll_fn(move || {
+            let acquired = {
+                let pending = pending.as_mut().expect("permit polled after acquiring it");
+                pending
+                    .permit
+                    .poll_acquire(&pending.semaphore)
+                    .map_err(|_| GcmError::ServerError)?
+            };
+            match acquired {
+                Async::Ready(()) => Ok(Async::Ready(pending.take().unwrap())),
+                Async::NotReady => Ok(Async::NotReady),
+            }
+        })
+    }
+}
+
+/// A slot taken from a `ConcurrencyLimiter`, given back on drop. Dropping it
+/// while still waiting gives up the wait.
+pub(crate) struct LimiterPermit {
+    semaphore: Arc<Semaphore>,
+    permit: Permit,
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        self.permit.release(&self.semaphore);
+    }
+}
@@ -1,22 +1,92 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
 use std::str;
+use std::str::FromStr;
 
-use serde::Serializer;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value as JsonValue};
 
 pub use message::response::*;
+pub use message::validate::{Advisory, Lint, Severity};
 use notification::Notification;
 
+#[cfg(test)]
+mod tests;
+
 pub mod async_sender;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod comparison;
 pub mod gcm_util;
+pub mod limiter;
+pub mod outcome;
+pub mod queue;
 pub mod response;
 pub mod sender;
+mod telemetry;
+pub mod transport;
+mod v1;
+pub mod validate;
 
-#[derive(PartialEq, Debug, Serialize)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
 pub enum Priority {
     Normal,
     High,
 }
 
+/// Parses `"normal"` and `"high"` in any casing. GCM itself only accepts the
+/// lowercase form, which is what `Message` always writes.
+impl FromStr for Priority {
+    type Err = GcmError;
+
+    fn from_str(s: &str) -> Result<Priority, GcmError> {
+        if s.eq_ignore_ascii_case("normal") {
+            Ok(Priority::Normal)
+        } else if s.eq_ignore_ascii_case("high") {
+            Ok(Priority::High)
+        } else {
+            Err(GcmError::InvalidMessage(format!("Unknown priority: {}", s)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Priority {
+    fn deserialize<D>(deserializer: D) -> Result<Priority, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PriorityVisitor;
+
+        impl<'de> Visitor<'de> for PriorityVisitor {
+            type Value = Priority;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("\"normal\" or \"high\"")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Priority, E> {
+                value.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_str(PriorityVisitor)
+    }
+}
+
+/// How `Message::data_values` treats values that are not JSON strings. GCM
+/// only accepts string values in `data`, anything else is silently mangled or
+/// rejected server side. Defaults to `Reject`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum DataValuePolicy {
+    /// Fail with `GcmError::InvalidMessage` naming the offending key.
+    #[default]
+    Reject,
+    /// Replace the value with its JSON text, e.g. `3` becomes `"3"` and `true` becomes `"true"`.
+    Stringify,
+}
+
 /// Represents a GCM message. Construct the GCM message
 /// using various utility methods and finally send it.
 /// # Examples:
@@ -25,11 +95,18 @@ pub enum Priority {
 ///
 /// let message = Message::new(vec!["<registration id>"]).dry_run(true);
 /// ```
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
+// the derived (de)serializers are inherent functions, the trait impls below
+// put the `message_id` fallback on top of them
+#[serde(remote = "Self")]
 pub struct Message<'a> {
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    to: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     registration_ids: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    condition: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
     collapse_key: Option<&'a str>,
     #[serde(
         skip_serializing_if = "Option::is_none",
@@ -42,14 +119,43 @@ pub struct Message<'a> {
     delay_while_idle: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     time_to_live: Option<i32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
     restricted_package_name: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     dry_run: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<HashMap<String, String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
     notification: Option<Notification<'a>>,
+    // not a legacy field, see `message_id`
+    #[serde(skip)]
+    message_id: Option<&'a str>,
+    #[serde(skip)]
+    apns_priority: Option<u8>,
+    // fields this crate does not know of, passed on to GCM as they are
+    #[serde(flatten)]
+    extra: Map<String, JsonValue>,
+}
+
+impl<'a> Serialize for Message<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.collapse_key, self.message_id) {
+            (None, Some(message_id)) => {
+                let legacy = Message {
+                    collapse_key: Some(message_id),
+                    ..self.clone()
+                };
+                Message::serialize(&legacy, serializer)
+            }
+            _ => Message::serialize(self, serializer),
+        }
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Message<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Message<'a>, D::Error> {
+        Message::deserialize(deserializer)
+    }
 }
 
 fn priority_lowercase<S>(
@@ -71,9 +177,11 @@ where
 impl<'a> Message<'a> {
     /// Get a new instance of Message. You need to supply either
     /// a registration id, or a topic (/topic/...).
-    pub fn new(registration_ids: Vec<&'a str>) -> Message {
+    pub fn new(registration_ids: Vec<&'a str>) -> Message<'a> {
         Message {
+            to: None,
             registration_ids: Some(registration_ids.iter().map(|s| s.to_string()).collect()),
+            condition: None,
             collapse_key: None,
             priority: None,
             content_available: None,
@@ -83,21 +191,158 @@ impl<'a> Message<'a> {
             dry_run: None,
             data: None,
             notification: None,
+            message_id: None,
+            apns_priority: None,
+            extra: Map::new(),
         }
     }
 
+    /// Get a message carrying both a visible notification and data for the app
+    /// to handle, the most common shape of a push. Like with `data`, GCM
+    /// expects every data value to be a string.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, NotificationBuilder};
+    /// use std::collections::HashMap;
+    ///
+    /// let mut data = HashMap::new();
+    /// data.insert("order_id", "1234");
+    ///
+    /// let notification = NotificationBuilder::new("Your order shipped").finalize();
+    /// let message = Message::notification_with_data(vec!["<registration id>"], notification, data);
+    /// ```
+    pub fn notification_with_data(
+        registration_ids: Vec<&'a str>,
+        notification: Notification<'a>,
+        data: HashMap<&'a str, &'a str>,
+    ) -> Message<'a> {
+        Message::new(registration_ids)
+            .notification(notification)
+            .data(data)
+    }
+
+    /// Read a message from JSON of unknown origin, e.g. the body of an API
+    /// call, and `validate` it. Fields of the wrong type fail with
+    /// `GcmError::InvalidMessage` naming the problem, as does a message GCM
+    /// would reject. Fields this crate does not know of are kept, see
+    /// `extra_fields`, and sent along to GCM.
+    /// # Examples:
+    /// ```rust
+    /// #[macro_use]
+    /// extern crate serde_json;
+    /// extern crate gcm;
+    ///
+    /// use gcm::Message;
+    ///
+    /// # fn main() {
+    /// let input = json!({"to": "<registration id>", "priority": "high", "fcm_options": {"analytics_label": "spring"}});
+    /// let message = Message::from_value(&input).unwrap();
+    /// assert!(message.extra_fields().contains_key("fcm_options"));
+    ///
+    /// assert!(Message::from_value(&json!({"to": "<registration id>", "time_to_live": "1h"})).is_err());
+    /// # }
+    /// ```
+    pub fn from_value(value: &'a JsonValue) -> Result<Message<'a>, GcmError> {
+        let message = Message::deserialize(value)
+            .map_err(|e| GcmError::InvalidMessage(format!("invalid message: {}", e)))?;
+        message.validate()?;
+        Ok(message)
+    }
+
+    /// The fields read by `from_value` or `Deserialize` that `Message` has no
+    /// setter for, such as newer GCM options. They are sent as they are.
+    pub fn extra_fields(&self) -> &Map<String, JsonValue> {
+        &self.extra
+    }
+
+    /// A copy of the message with all its payload and settings, sent to `ids`
+    /// instead of its own recipients, e.g. to send one template to several
+    /// segments of an audience. Any `to` or `condition` is dropped.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, NotificationBuilder};
+    ///
+    /// let template = Message::new(vec![])
+    ///     .notification(NotificationBuilder::new("Spring sale").body("20% off").finalize());
+    ///
+    /// let europe = template.clone_with_ids(vec!["<registration id>".to_string()]);
+    /// let asia = template.clone_with_ids(vec!["<other registration id>".to_string()]);
+    /// ```
+    pub fn clone_with_ids(&self, ids: Vec<String>) -> Message<'a> {
+        let mut message = self.clone();
+        message.to = None;
+        message.condition = None;
+        message.registration_ids = Some(ids);
+        message
+    }
+
     /// Set various registration ids to which the message ought to be sent.
     pub fn registration_ids(mut self, ids: Vec<&'a str>) -> Message<'a> {
         self.registration_ids = Some(ids.iter().map(|s| s.to_string()).collect());
         self
     }
 
+    /// Send the message to a single registration id or to a topic (`/topics/...`)
+    /// instead. GCM refuses requests with more than one kind of recipient, so
+    /// this drops any registration ids and condition set before.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::Message;
+    ///
+    /// let message = Message::new(vec![]).to("/topics/news");
+    /// ```
+    pub fn to(mut self, to: &'a str) -> Message<'a> {
+        self.to = Some(to);
+        self.registration_ids = None;
+        self.condition = None;
+        self
+    }
+
+    /// Whether `to` addresses a topic (`/topics/...`) rather than a single
+    /// registration id.
+    pub fn to_is_topic(&self) -> bool {
+        self.to.is_some_and(|to| to.starts_with("/topics/"))
+    }
+
+    /// Send the message to every device subscribed to a combination of topics,
+    /// e.g. `'dogs' in topics || 'cats' in topics`. Drops any registration ids
+    /// and `to` set before.
+    pub fn condition(mut self, condition: &'a str) -> Message<'a> {
+        self.condition = Some(condition);
+        self.registration_ids = None;
+        self.to = None;
+        self
+    }
+
     /// Set this parameter to identify groups of messages that can be collapsed.
     pub fn collapse_key(mut self, collapse_key: &'a str) -> Message<'a> {
         self.collapse_key = Some(collapse_key);
         self
     }
 
+    /// Set a client supplied id for the message, so that sending it again
+    /// (e.g. when retrying after a timeout) does not show it twice.
+    ///
+    /// The legacy API has no such field, the closest is `collapse_key`: the id
+    /// becomes the collapse key unless one is set, and a device offline for
+    /// both sends only gets the latest one. In a v1 message (`to_v1`) the id
+    /// stays separate from the collapse key and goes into the
+    /// `apns-collapse-id` header, where APNs coalesces notifications sharing
+    /// it; Android uses it as collapse key only if none is set.
+    ///
+    /// The id must be non-empty ASCII without whitespace and at most 64 bytes
+    /// long (the `apns-collapse-id` limit), `validate` rejects anything else.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::Message;
+    ///
+    /// let message = Message::new(vec!["<registration id>"]).message_id("order-1234-shipped");
+    /// ```
+    pub fn message_id(mut self, message_id: &'a str) -> Message<'a> {
+        self.message_id = Some(message_id);
+        self
+    }
+
     /// Set the priority of the message. You can set Normal or High priorities.
     /// # Examples:
     /// ```rust
@@ -111,7 +356,26 @@ impl<'a> Message<'a> {
         self
     }
 
-    /// To set the `content-available` field on iOS
+    /// Set the `apns-priority` of a v1 message (`to_v1`), 10 to deliver right
+    /// away or 5 to let iOS pick a battery friendly moment. This is
+    /// independent of `priority`, which only drives Android: a chat message
+    /// can go out high priority on Android and still be 5 on iOS. The legacy
+    /// API has no equivalent and ignores it. `validate` rejects other values.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, Priority};
+    ///
+    /// let message = Message::new(vec!["<registration id>"])
+    ///     .priority(Priority::High)
+    ///     .apns_priority(5);
+    /// ```
+    pub fn apns_priority(mut self, apns_priority: u8) -> Message<'a> {
+        self.apns_priority = Some(apns_priority);
+        self
+    }
+
+    /// To set the `content-available` field on iOS. For silent data pushes
+    /// that should also wake Android devices, send with `Priority::High`.
     pub fn content_available(mut self, content_available: bool) -> Message<'a> {
         self.content_available = Some(content_available);
         self
@@ -164,6 +428,48 @@ impl<'a> Message<'a> {
         self
     }
 
+    /// Set the custom key-value pairs of the message from a JSON object, dealing
+    /// with non-string values according to `policy`.
+    /// # Examples:
+    /// ```rust
+    /// #[macro_use]
+    /// extern crate serde_json;
+    /// extern crate gcm;
+    ///
+    /// use gcm::{DataValuePolicy, Message};
+    ///
+    /// # fn main() {
+    /// let data = json!({"score": 3, "final": true});
+    /// let data = data.as_object().unwrap();
+    ///
+    /// assert!(Message::new(vec!["<registration id>"]).data_values(data, DataValuePolicy::Reject).is_err());
+    /// assert!(Message::new(vec!["<registration id>"]).data_values(data, DataValuePolicy::Stringify).is_ok());
+    /// # }
+    /// ```
+    pub fn data_values(
+        mut self,
+        data: &Map<String, JsonValue>,
+        policy: DataValuePolicy,
+    ) -> Result<Message<'a>, GcmError> {
+        let mut datamap: HashMap<String, String> = HashMap::new();
+        for (key, val) in data.iter() {
+            let val = match *val {
+                JsonValue::String(ref s) => s.clone(),
+                ref other if policy == DataValuePolicy::Stringify => other.to_string(),
+                _ => {
+                    return Err(GcmError::InvalidMessage(format!(
+                        "data value for key `{}` is not a string",
+                        key
+                    )))
+                }
+            };
+            datamap.insert(key.to_string(), val);
+        }
+
+        self.data = Some(datamap);
+        Ok(self)
+    }
+
     /// Use this to set a `Notification` for the message.
     /// # Examples:
     /// ```rust
@@ -181,7 +487,269 @@ impl<'a> Message<'a> {
         self
     }
 
+    /// Apply `f` to the message only when `cond` holds, keeping the builder
+    /// chain intact for conditional configuration.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, Priority};
+    ///
+    /// let urgent = true;
+    /// let message = Message::new(vec!["<registration id>"])
+    ///     .apply_if(urgent, |m| m.priority(Priority::High))
+    ///     .apply_if(!urgent, |m| m.collapse_key("digest"));
+    /// ```
+    pub fn apply_if<F>(self, cond: bool, f: F) -> Message<'a>
+    where
+        F: FnOnce(Message<'a>) -> Message<'a>,
+    {
+        if cond {
+            f(self)
+        } else {
+            self
+        }
+    }
+
+    /// Whether the message shows a notification, i.e. carries a notification
+    /// block, with or without data.
+    pub fn is_notification(&self) -> bool {
+        self.notification.is_some()
+    }
+
+    /// Whether the message is a silent push: only data and/or
+    /// `content_available`, nothing shown to the user.
+    pub fn is_silent(&self) -> bool {
+        self.notification.is_none()
+            && (self.data.is_some() || self.content_available == Some(true))
+    }
+
+    /// Size of the `data` payload in bytes, counted the way GCM does for its
+    /// 4KB limit: the UTF-8 length of every key plus its value.
+    pub fn data_size(&self) -> usize {
+        self.data_sizes().iter().map(|&(_, size)| size).sum()
+    }
+
+    /// What each `data` key contributes to `data_size`, biggest first, to see
+    /// which keys to trim when a payload gets too large.
+    pub fn data_sizes(&self) -> Vec<(String, usize)> {
+        let mut sizes: Vec<(String, usize)> = match self.data {
+            Some(ref data) => data
+                .iter()
+                .map(|(key, value)| (key.clone(), key.len() + value.len()))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        sizes
+    }
+
+    /// The message as the JSON text the senders post to GCM.
+    pub fn to_json_string(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// The message as the UTF-8 JSON bytes the senders post to GCM, for
+    /// relaying the exact payload over another protocol.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// Layer `other` on top of this message, e.g. campaign specific overrides on
+    /// top of a base template. Every field set in `other` replaces the one of
+    /// `self`, fields `other` leaves unset are kept. The `data` maps are merged
+    /// key by key, values of `other` winning for keys present in both. The
+    /// recipients (registration ids, `to` or `condition`) are taken as a whole
+    /// from `other` if it has any; an empty registration id list counts as none,
+    /// so overrides can be started off with `Message::new(vec![])`.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, Priority};
+    ///
+    /// let base = Message::new(vec!["<registration id>"]).time_to_live(3600);
+    /// let message = base.merge(Message::new(vec![]).priority(Priority::High));
+    /// ```
+    pub fn merge(self, other: Message<'a>) -> Message<'a> {
+        let data = match (self.data, other.data) {
+            (Some(mut data), Some(overrides)) => {
+                data.extend(overrides);
+                Some(data)
+            }
+            (data, overrides) => overrides.or(data),
+        };
+
+        let mut extra = self.extra;
+        extra.extend(other.extra);
+
+        let other_ids = other.registration_ids.filter(|ids| !ids.is_empty());
+        let (to, registration_ids, condition) =
+            if other.to.is_some() || other_ids.is_some() || other.condition.is_some() {
+                (other.to, other_ids, other.condition)
+            } else {
+                (self.to, self.registration_ids, self.condition)
+            };
+
+        Message {
+            to,
+            registration_ids,
+            condition,
+            collapse_key: other.collapse_key.or(self.collapse_key),
+            priority: other.priority.or(self.priority),
+            content_available: other.content_available.or(self.content_available),
+            delay_while_idle: other.delay_while_idle.or(self.delay_while_idle),
+            time_to_live: other.time_to_live.or(self.time_to_live),
+            restricted_package_name: other.restricted_package_name.or(self.restricted_package_name),
+            dry_run: other.dry_run.or(self.dry_run),
+            data,
+            notification: other.notification.or(self.notification),
+            message_id: other.message_id.or(self.message_id),
+            apns_priority: other.apns_priority.or(self.apns_priority),
+            extra,
+        }
+    }
+
+    /// The serialized fields of this message that differ from `base`, to store
+    /// many messages derived from one template as the template plus a small
+    /// delta. Merging the delta (deserialized as a `Message`) into `base`
+    /// gives this message back.
+    ///
+    /// The recipients are one field here as in `merge`: if they differ at all,
+    /// all of this message's recipients are in the delta. `data` is diffed
+    /// per key, only the keys that are new or carry another value are kept.
+    /// Fields and data keys removed relative to `base` cannot be expressed
+    /// and are not in the delta; neither are the v1-only `message_id` and
+    /// `apns_priority`, which are not serialized.
+    /// # Examples:
+    /// ```rust
+    /// extern crate gcm;
+    /// extern crate serde;
+    ///
+    /// use gcm::{Message, Priority};
+    /// use serde::Deserialize;
+    ///
+    /// # fn main() {
+    /// let template = Message::new(vec!["a", "b"]).collapse_key("scores");
+    /// let message = template.clone().priority(Priority::High);
+    ///
+    /// let delta = message.diff(&template);
+    /// assert_eq!(delta.to_string(), r#"{"priority":"high"}"#);
+    ///
+    /// let restored = template.merge(Message::deserialize(&delta).unwrap());
+    /// assert_eq!(restored.to_json_string().unwrap(), message.to_json_string().unwrap());
+    /// # }
+    /// ```
+    pub fn diff(&self, base: &Message) -> JsonValue {
+        let mut fields = json_fields(self);
+        let base = json_fields(base);
+
+        let recipients_differ = RECIPIENT_KEYS
+            .iter()
+            .any(|key| fields.get(*key) != base.get(*key));
+        fields.retain(|key, value| {
+            if RECIPIENT_KEYS.contains(&key.as_str()) {
+                recipients_differ
+            } else {
+                base.get(key) != Some(value)
+            }
+        });
+
+        if let (Some(JsonValue::Object(data)), Some(JsonValue::Object(base_data))) =
+            (fields.get_mut("data"), base.get("data"))
+        {
+            data.retain(|key, value| base_data.get(key) != Some(value));
+        }
+        if fields.get("data").and_then(JsonValue::as_object).is_some_and(Map::is_empty) {
+            fields.remove("data");
+        }
+
+        JsonValue::Object(fields)
+    }
+
+    /// Split the registration ids into groups sharing the same `key`, getting
+    /// one message per group that otherwise carries the same settings. Groups
+    /// come back ordered by key, ids keep their order within a group. Useful for
+    /// sending with per-token priority or package, see `GcmSender::send_operation`.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, Priority};
+    ///
+    /// let vip = vec!["token-2"];
+    /// let messages: Vec<Message> = Message::new(vec!["token-1", "token-2", "token-3"])
+    ///     .split_by(|token| vip.contains(&token))
+    ///     .into_iter()
+    ///     .map(|(is_vip, message)| message.apply_if(is_vip, |m| m.priority(Priority::High)))
+    ///     .collect();
+    ///
+    /// assert_eq!(messages.len(), 2);
+    /// ```
+    pub fn split_by<K, F>(self, key: F) -> Vec<(K, Message<'a>)>
+    where
+        K: Ord,
+        F: Fn(&str) -> K,
+    {
+        let mut groups: BTreeMap<K, Vec<String>> = BTreeMap::new();
+        for id in self.registration_ids.clone().unwrap_or_default() {
+            groups.entry(key(&id)).or_default().push(id);
+        }
+
+        groups
+            .into_iter()
+            .map(|(key, ids)| {
+                let mut message = self.clone();
+                message.registration_ids = Some(ids);
+                (key, message)
+            })
+            .collect()
+    }
+
     pub fn build(self) -> Message<'a> {
         self
     }
 }
+
+const RECIPIENT_KEYS: &[&str] = &["to", "registration_ids", "condition"];
+
+fn json_fields(message: &Message) -> Map<String, JsonValue> {
+    match serde_json::to_value(message) {
+        Ok(JsonValue::Object(fields)) => fields,
+        _ => Map::new(),
+    }
+}
+
+/// Builds a message for a single recipient from its address. A `/topics/`
+/// prefix makes it a topic message, and the topic name must then match
+/// `[a-zA-Z0-9-_.~%]+` as GCM requires. Anything else is taken as a
+/// registration id. There is no separate `From<&str>`: it would clash with
+/// this impl, and a topic name can be invalid.
+/// # Examples:
+/// ```rust
+/// use std::convert::TryFrom;
+/// use gcm::Message;
+///
+/// let news = Message::try_from("/topics/news").unwrap();
+/// assert!(news.to_is_topic());
+///
+/// let device = Message::try_from("<registration id>").unwrap();
+/// assert!(!device.to_is_topic());
+///
+/// assert!(Message::try_from("/topics/breaking news").is_err());
+/// ```
+impl<'a> TryFrom<&'a str> for Message<'a> {
+    type Error = GcmError;
+
+    fn try_from(to: &'a str) -> Result<Message<'a>, GcmError> {
+        if let Some(topic) = to.strip_prefix("/topics/") {
+            let valid = !topic.is_empty()
+                && topic
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.~%".contains(c));
+            if !valid {
+                return Err(GcmError::InvalidMessage(format!(
+                    "invalid topic name `{}`",
+                    topic
+                )));
+            }
+        }
+
+        Ok(Message::new(vec![]).to(to))
+    }
+}
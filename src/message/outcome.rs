@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::{Map, Value as JsonValue};
+
+use message::response::{GcmError, GcmResponse};
+
+/// Ties together the requests made for one logical send, e.g. the per-priority
+/// parts of a split audience, so logs and metrics can treat them as one action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Correlation {
+    pub operation_id: String,
+    /// Position of the request among the ones sent for the operation.
+    pub index: usize,
+}
+
+/// What happened to a single request sent by `GcmSender`.
+#[derive(Debug)]
+pub struct SendOutcome {
+    pub correlation: Correlation,
+    pub result: Result<GcmResponse, GcmError>,
+    /// Number of times the request was posted, retries included.
+    pub attempts: u32,
+    /// Time from serializing the message to having parsed the answer.
+    pub latency: Duration,
+    /// Every attempt made, only captured with `GcmSender::with_diagnostics`.
+    pub retry_history: Option<RetryHistory>,
+    /// Where `latency` went, only captured with `GcmSender::with_diagnostics`.
+    pub timings: Option<Timings>,
+    /// `gcm_util::payload_hash` of the posted body, only computed with
+    /// `GcmSender::with_payload_hashing` or `with_duplicate_detection`.
+    pub payload_hash: Option<u64>,
+    /// Whether the same body was sent shortly before, see
+    /// `GcmSender::with_duplicate_detection`. The request went out regardless.
+    pub duplicate: bool,
+}
+
+impl SendOutcome {
+    /// A flat JSON object summing up the outcome for log aggregators, the same
+    /// fields for every service: `operation_id`, `index`, `status` (`ok` or
+    /// `error`), `attempts`, `latency_ms`, and `multicast_id`, `success`,
+    /// `failure` and `canonical_ids` of a response or `error` with the kind of
+    /// error, plus `payload_hash` as hex and `duplicate` when hashing is on.
+    /// Registration ids, message ids, payloads and keys never show up,
+    /// and neither does the text of an error, which may quote the request.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::Message;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// let outcome = sender.send_with_outcome(Message::new(vec!["<registration id>"]));
+    /// println!("{}", outcome.to_event());
+    /// ```
+    pub fn to_event(&self) -> JsonValue {
+        let mut event = Map::new();
+        event.insert("operation_id".to_string(), self.correlation.operation_id.clone().into());
+        event.insert("index".to_string(), self.correlation.index.into());
+        event.insert("attempts".to_string(), self.attempts.into());
+        event.insert("latency_ms".to_string(), (self.latency.as_millis() as u64).into());
+        if let Some(hash) = self.payload_hash {
+            event.insert("payload_hash".to_string(), format!("{:016x}", hash).into());
+            event.insert("duplicate".to_string(), self.duplicate.into());
+        }
+
+        match self.result {
+            Ok(ref response) => {
+                event.insert("status".to_string(), "ok".into());
+                event.insert("multicast_id".to_string(), response.multicast_id.into());
+                event.insert("success".to_string(), response.success_count().into());
+                event.insert("failure".to_string(), response.failure_count().into());
+                event.insert("canonical_ids".to_string(), response.canonical_ids.into());
+            }
+            Err(ref error) => {
+                event.insert("status".to_string(), "error".into());
+                event.insert("error".to_string(), error_kind(error).into());
+            }
+        }
+
+        JsonValue::Object(event)
+    }
+}
+
+/// A fixed name for the kind of `error`, free of the response bodies and
+/// registration ids some errors carry.
+pub(crate) fn error_kind(error: &GcmError) -> &'static str {
+    match *error {
+        GcmError::Unauthorized => "Unauthorized",
+        GcmError::InvalidMessage(_) => "InvalidMessage",
+        GcmError::InvalidRequest(_) => "InvalidRequest",
+        GcmError::ServerError => "ServerError",
+        GcmError::InvalidJsonBody => "InvalidJsonBody",
+        GcmError::Gateway { .. } => "Gateway",
+        GcmError::PermanentFailures(_) => "PermanentFailures",
+        GcmError::QueueClosed => "QueueClosed",
+    }
+}
+
+/// The parts of a send's latency, to tell a huge payload from a slow network.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timings {
+    /// Turning the message into the posted body, body transforms included.
+    pub serialization: Duration,
+    /// Posting the body and reading the answer, summed over all attempts;
+    /// waits between retries are not included.
+    pub network: Duration,
+}
+
+/// The attempts made for one request, in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryHistory {
+    pub attempts: Vec<AttemptRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttemptRecord {
+    /// HTTP status of the answer, `None` if none was received.
+    pub status: Option<u16>,
+    /// How long the sender waited before this attempt, zero for the first one.
+    pub delay: Duration,
+    /// Why the attempt failed, `None` for the successful one.
+    pub error: Option<GcmError>,
+}
+
+/// How a dry run to a random sample of an audience went, see
+/// `GcmSender::dry_run_sample`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DryRunSummary {
+    /// Number of registration ids in the sample.
+    pub sampled: usize,
+    pub success: usize,
+    pub failure: usize,
+    /// Number of sampled ids per error GCM reported.
+    pub errors: HashMap<String, usize>,
+}
+
+impl DryRunSummary {
+    /// Share of the sample that failed, between 0 and 1.
+    pub fn failure_rate(&self) -> f64 {
+        if self.sampled == 0 {
+            0.0
+        } else {
+            self.failure as f64 / self.sampled as f64
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tokio::runtime::current_thread::Runtime;
+
+use gcm_util;
+use message::async_sender::{self, AsyncGsmSender};
+use message::response::{GcmError, GcmResponse};
+use message::validate::MAX_REGISTRATION_IDS;
+use message::Message;
+
+/// Told how every request of a `SendQueue` went, with the ids it went to.
+pub type ResultHandler = Box<dyn Fn(&[String], Result<GcmResponse, GcmError>) + Send + Sync>;
+
+/// One request waiting in the queue.
+struct Job {
+    body: String,
+    ids: Vec<String>,
+}
+
+/// A bounded queue of messages sent in the background by worker threads, for
+/// fire-and-forget pushes. `enqueue` blocks while the queue is full, so a
+/// producer faster than GCM is slowed down rather than piling up messages.
+/// Audiences over 1000 ids are split into several requests, and requests
+/// failing with a retryable error (see `GcmError::is_retryable`) are retried.
+///
+/// Workers are started by the first `enqueue`, the settings have to be made
+/// before. Dropping the queue lets the workers finish what is queued in the
+/// background; `shutdown` waits for them.
+/// # Examples:
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use gcm::async_sender::AsyncGsmSender;
+/// use gcm::queue::SendQueue;
+/// use gcm::Message;
+///
+/// let sender = AsyncGsmSender::new("<api-key>".to_string(), "https://fcm.googleapis.com/fcm/send".to_string(), false);
+/// let queue = SendQueue::new(sender, 1000)
+///     .with_workers(4)
+///     .with_retries(3, Duration::from_millis(500))
+///     .with_result_handler(Box::new(|ids, result| {
+///         if let Err(error) = result {
+///             eprintln!("sending to {} ids failed: {}", ids.len(), error);
+///         }
+///     }));
+///
+/// queue.enqueue(Message::new(vec![]).collapse_key("scores"), vec!["<registration id>".to_string()]).unwrap();
+/// queue.shutdown();
+/// ```
+pub struct SendQueue {
+    sender: AsyncGsmSender,
+    jobs: Option<SyncSender<Job>>,
+    pending: Arc<Mutex<Receiver<Job>>>,
+    worker_count: usize,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    on_result: Option<Arc<ResultHandler>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl SendQueue {
+    /// Get a queue holding up to `capacity` requests not yet picked up by a
+    /// worker, sent through `sender` by a single worker without retries.
+    pub fn new(sender: AsyncGsmSender, capacity: usize) -> SendQueue {
+        let (jobs, pending) = mpsc::sync_channel(capacity);
+        SendQueue {
+            sender,
+            jobs: Some(jobs),
+            pending: Arc::new(Mutex::new(pending)),
+            worker_count: 1,
+            max_retries: 0,
+            retry_base_delay: Duration::from_secs(1),
+            on_result: None,
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Send with `count` workers, each with one request in flight at a time.
+    /// A `ConcurrencyLimiter` on the sender bounds them further.
+    pub fn with_workers(mut self, count: usize) -> SendQueue {
+        self.worker_count = count.max(1);
+        self
+    }
+
+    /// Retry requests like `GcmSender::with_retries` does, the worker waiting
+    /// `base_delay * 2^(n-1)`, capped at a minute, before the n-th retry,
+    /// unless the answer asked for a specific wait with `Retry-After`.
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> SendQueue {
+        self.max_retries = max_retries;
+        self.retry_base_delay = base_delay;
+        self
+    }
+
+    /// Hand the outcome of every request, retries done, to `handler`. It runs
+    /// on the worker, which sends nothing else meanwhile.
+    pub fn with_result_handler(mut self, handler: ResultHandler) -> SendQueue {
+        self.on_result = Some(Arc::new(handler));
+        self
+    }
+
+    /// Queue `msg` for the registration ids `ids`, waiting for room if the
+    /// queue is full. The recipients of `msg` itself are ignored. Fails if
+    /// `msg` cannot be serialized, or with `GcmError::QueueClosed` if the
+    /// workers are gone. More than 1000 ids are queued as several requests,
+    /// one after the other: when the workers go away midway, the requests
+    /// queued before stay queued and are not taken back.
+    pub fn enqueue(&self, msg: Message, ids: Vec<String>) -> Result<(), GcmError> {
+        let base = async_sender::template_body(&msg)?;
+        self.start_workers();
+
+        let jobs = self.jobs.as_ref().ok_or(GcmError::QueueClosed)?;
+        for batch in ids.chunks(MAX_REGISTRATION_IDS) {
+            let mut body = base.clone();
+            body["registration_ids"] = batch.to_vec().into();
+            let job = Job {
+                body: body.to_string(),
+                ids: batch.to_vec(),
+            };
+            jobs.send(job).map_err(|_| GcmError::QueueClosed)?;
+        }
+        Ok(())
+    }
+
+    /// Stop taking messages and wait until every queued one is sent.
+    pub fn shutdown(mut self) {
+        // workers stop once the queue is closed and empty
+        self.jobs = None;
+        let workers = self.workers.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for worker in workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+
+    fn start_workers(&self) {
+        let mut workers = self.workers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !workers.is_empty() {
+            return;
+        }
+
+        for _ in 0..self.worker_count {
+            let worker = Worker {
+                sender: self.sender.clone(),
+                pending: self.pending.clone(),
+                max_retries: self.max_retries,
+                retry_base_delay: self.retry_base_delay,
+                on_result: self.on_result.clone(),
+            };
+            workers.push(thread::spawn(move || worker.run()));
+        }
+    }
+}
+
+struct Worker {
+    sender: AsyncGsmSender,
+    pending: Arc<Mutex<Receiver<Job>>>,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    on_result: Option<Arc<ResultHandler>>,
+}
+
+impl Worker {
+    fn run(self) {
+        let mut runtime = Runtime::new().expect("new queue worker runtime");
+        loop {
+            // the lock is only held while waiting for the next job
+            let job = match self.pending.lock() {
+                Ok(pending) => pending.recv(),
+                Err(_) => return,
+            };
+            let job = match job {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+
+            let result = self.send(&mut runtime, &job);
+            if let Some(ref on_result) = self.on_result {
+                on_result(&job.ids, result);
+            }
+        }
+    }
+
+    fn send(&self, runtime: &mut Runtime, job: &Job) -> Result<GcmResponse, GcmError> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = runtime.block_on(self.sender.limited_post(job.body.clone(), job.ids.clone()));
+            match result {
+                Err((ref error, retry_after))
+                    if error.is_retryable() && attempts <= self.max_retries =>
+                {
+                    let delay = gcm_util::retry_delay(self.retry_base_delay, attempts, retry_after);
+                    thread::sleep(delay);
+                }
+                result => return result.map_err(|(error, _)| error),
+            }
+        }
+    }
+}
@@ -2,10 +2,15 @@ use std::error;
 use std::fmt::{self, Display};
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Serialize)]
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::Value as JsonValue;
+
+#[derive(Deserialize, Debug, Default, Serialize)]
 pub struct GcmResponse {
     pub message_id: Option<u64>,
     pub error: Option<String>,
+    /// Explanation of `error`, when GCM or the proxy in between gives one.
+    pub error_description: Option<String>,
     pub multicast_id: Option<i64>,
     pub success: Option<u64>,
     pub failure: Option<u64>,
@@ -15,21 +20,211 @@ pub struct GcmResponse {
 }
 
 impl GcmResponse {
-    pub fn default() -> GcmResponse {
-        GcmResponse {
-            message_id: None,
-            error: None,
-            multicast_id: None,
-            success: None,
-            failure: None,
-            canonical_ids: None,
-            results: None,
-            ids_by_error:None,
+    /// Parse a response body without collecting the `results` array: every
+    /// `MessageResult` is handed to `on_result` as soon as it is read, which keeps
+    /// memory flat for large multicast responses. The returned `GcmResponse`
+    /// carries all other fields, its `results` is always `None`.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::GcmResponse;
+    ///
+    /// let body = r#"{"multicast_id":1,"success":1,"failure":1,"results":[{"message_id":"0:1"},{"error":"NotRegistered"}]}"#;
+    ///
+    /// let mut failures = 0;
+    /// let response = GcmResponse::parse_streaming(body, |result| {
+    ///     if result.error.is_some() {
+    ///         failures += 1;
+    ///     }
+    /// }).unwrap();
+    ///
+    /// assert_eq!(response.failure, Some(failures));
+    /// ```
+    pub fn parse_streaming<F>(body: &str, on_result: F) -> Result<GcmResponse, GcmError>
+    where
+        F: FnMut(MessageResult),
+    {
+        let mut deserializer = serde_json::Deserializer::from_str(body);
+        let response = deserializer
+            .deserialize_map(StreamingVisitor { on_result })
+            .map_err(|_| GcmError::InvalidJsonBody)?;
+        deserializer.end().map_err(|_| GcmError::InvalidJsonBody)?;
+        Ok(response)
+    }
+
+    /// Parse a response body whose envelope may differ from the documented
+    /// multicast one, as answered by some proxies. Accepted are, in this order:
+    ///
+    /// * the multicast response, recognised by its `multicast_id`,
+    /// * an object with nothing but a `results` array,
+    /// * the result of a single message, an object with a `message_id`
+    ///   (string or number, as topic sends answer) or an `error`.
+    ///
+    /// Anything else is `GcmError::InvalidJsonBody`.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{GcmResponse, LenientResponse};
+    ///
+    /// match GcmResponse::parse_lenient(r#"{"message_id": 7}"#).unwrap() {
+    ///     LenientResponse::Single(result) => assert_eq!(result.message_id, Some("7".to_string())),
+    ///     other => panic!("unexpected {:?}", other),
+    /// }
+    /// ```
+    pub fn parse_lenient(body: &str) -> Result<LenientResponse, GcmError> {
+        let value: JsonValue = serde_json::from_str(body).map_err(|_| GcmError::InvalidJsonBody)?;
+        let fields = value.as_object().ok_or(GcmError::InvalidJsonBody)?;
+
+        if fields.contains_key("multicast_id") {
+            return serde_json::from_value(value)
+                .map(LenientResponse::Multicast)
+                .map_err(|_| GcmError::InvalidJsonBody);
+        }
+        if let Some(results) = fields.get("results") {
+            return serde_json::from_value(results.clone())
+                .map(LenientResponse::Results)
+                .map_err(|_| GcmError::InvalidJsonBody);
         }
+
+        let message_id = match fields.get("message_id") {
+            Some(JsonValue::String(id)) => Some(id.clone()),
+            Some(JsonValue::Number(id)) => Some(id.to_string()),
+            Some(_) => return Err(GcmError::InvalidJsonBody),
+            None => None,
+        };
+        let error = match fields.get("error") {
+            Some(JsonValue::String(error)) => Some(error.clone()),
+            Some(_) => return Err(GcmError::InvalidJsonBody),
+            None => None,
+        };
+        if message_id.is_none() && error.is_none() {
+            return Err(GcmError::InvalidJsonBody);
+        }
+
+        Ok(LenientResponse::Single(MessageResult {
+            message_id,
+            registration_id: fields.get("registration_id").and_then(JsonValue::as_u64),
+            error,
+            error_description: fields
+                .get("error_description")
+                .and_then(JsonValue::as_str)
+                .map(str::to_string),
+        }))
+    }
+
+    /// Number of messages delivered: the `success` count, or when the response
+    /// has none, the results without an error.
+    pub fn success_count(&self) -> u64 {
+        self.success
+            .unwrap_or_else(|| self.count_results(|result| result.error.is_none()))
+    }
+
+    /// Number of messages that failed: the `failure` count, or when the
+    /// response has none, the results carrying an error.
+    pub fn failure_count(&self) -> u64 {
+        self.failure
+            .unwrap_or_else(|| self.count_results(|result| result.error.is_some()))
+    }
+
+    /// Set the aggregate counts from `results`, after results were replaced.
+    pub(crate) fn recount(&mut self) {
+        if self.results.is_some() {
+            self.success = Some(self.count_results(|result| result.error.is_none()));
+            self.failure = Some(self.count_results(|result| result.error.is_some()));
+            self.canonical_ids = Some(self.count_results(|result| result.registration_id.is_some()));
+        }
+    }
+
+    fn count_results<F: Fn(&MessageResult) -> bool>(&self, matches: F) -> u64 {
+        self.results
+            .iter()
+            .flatten()
+            .filter(|result| matches(result))
+            .count() as u64
+    }
+
+    /// Key the results by the registration id they belong to. `sent_ids` are the
+    /// ids of the request, in the order they were sent. A token sent more than
+    /// once maps to its last result.
+    pub fn as_map<S: AsRef<str>>(&self, sent_ids: &[S]) -> HashMap<String, MessageResult> {
+        let results = match self.results {
+            Some(ref results) => results,
+            None => return HashMap::new(),
+        };
+
+        sent_ids
+            .iter()
+            .zip(results.iter())
+            .map(|(id, result)| (id.as_ref().to_string(), result.clone()))
+            .collect()
+    }
+
+    /// All registration ids whose result carries an error, whatever the cause,
+    /// in the order they were sent. Meant for reporting failures; not every id
+    /// in here should be dropped, transient errors such as `Unavailable` are
+    /// worth another try.
+    pub fn failed_registration_ids<S: AsRef<str>>(&self, sent_ids: &[S]) -> Vec<String> {
+        let results = match self.results {
+            Some(ref results) => results,
+            None => return Vec::new(),
+        };
+
+        sent_ids
+            .iter()
+            .zip(results.iter())
+            .filter(|&(_, result)| result.error.is_some())
+            .map(|(id, _)| id.as_ref().to_string())
+            .collect()
+    }
+
+    /// Sort the registration ids of the request into what to do about them,
+    /// in one pass over the results. `sent_ids` are the ids of the request,
+    /// in the order they were sent. Ids delivered as they are, and ids failing
+    /// for reasons of the message rather than the token (e.g. `MessageTooBig`),
+    /// end up in none of the sets.
+    /// # Examples:
+    /// ```rust
+    /// extern crate gcm;
+    /// extern crate serde_json;
+    ///
+    /// use gcm::GcmResponse;
+    ///
+    /// # fn main() {
+    /// let body = r#"{"multicast_id":1,"success":1,"failure":2,"canonical_ids":1,"results":[
+    ///     {"message_id":"0:1","registration_id":42},{"error":"Unavailable"},{"error":"NotRegistered"}]}"#;
+    /// let response: GcmResponse = serde_json::from_str(body).unwrap();
+    ///
+    /// let partitioned = response.partition(&["a", "b", "c"]);
+    /// assert_eq!(partitioned.retry, vec!["b".to_string()]);
+    /// assert_eq!(partitioned.remove, vec!["c".to_string()]);
+    /// assert_eq!(partitioned.canonical, vec![("a".to_string(), 42)]);
+    /// # }
+    /// ```
+    pub fn partition<S: AsRef<str>>(&self, sent_ids: &[S]) -> Partitioned {
+        let mut partitioned = Partitioned::default();
+        let results = match self.results {
+            Some(ref results) => results,
+            None => return partitioned,
+        };
+
+        for (id, result) in sent_ids.iter().zip(results.iter()) {
+            let id = id.as_ref().to_string();
+            match result.reason() {
+                Some(ErrorReason::NotRegistered)
+                | Some(ErrorReason::InvalidRegistration)
+                | Some(ErrorReason::MismatchSenderId) => partitioned.remove.push(id),
+                Some(ref reason) if !reason.is_permanent() => partitioned.retry.push(id),
+                Some(_) => {}
+                None => {
+                    if let Some(canonical_id) = result.registration_id {
+                        partitioned.canonical.push((id, canonical_id));
+                    }
+                }
+            }
+        }
+        partitioned
     }
 
     pub fn build_reg_ids_by_error_map(&mut self, ids :Vec<String>){
-        if self.failure.is_none() || self.results.is_none(){
+        if self.results.is_none(){
             return
         }
 
@@ -40,7 +235,11 @@ impl GcmResponse {
             match v.error {
                 None => {},
                 Some(ref err_name) => {
-                    let id = ids.get(i).unwrap();
+                    // more results than ids sent, the rest cannot be told apart
+                    let id = match ids.get(i) {
+                        Some(id) => id,
+                        None => break,
+                    };
                     ids_by_error
                         .entry(err_name.to_string())
                         .and_modify(|v| v.push(id.to_string()))
@@ -54,19 +253,231 @@ impl GcmResponse {
     }
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+/// The registration ids of a request sorted by `GcmResponse::partition`. An
+/// id is in at most one of the sets, in the order it was sent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Partitioned {
+    /// Ids failing for the time being, worth sending to again later.
+    pub retry: Vec<String>,
+    /// Ids that are no longer valid, to drop from the store.
+    pub remove: Vec<String>,
+    /// Ids delivered under a newer registration id, paired with that id, to
+    /// replace them with in the store.
+    pub canonical: Vec<(String, u64)>,
+}
+
+struct StreamingVisitor<F> {
+    on_result: F,
+}
+
+impl<'de, F> Visitor<'de> for StreamingVisitor<F>
+where
+    F: FnMut(MessageResult),
+{
+    type Value = GcmResponse;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a GCM response object")
+    }
+
+    fn visit_map<A>(mut self, mut map: A) -> Result<GcmResponse, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut response = GcmResponse::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "message_id" => response.message_id = map.next_value()?,
+                "error" => response.error = map.next_value()?,
+                "error_description" => response.error_description = map.next_value()?,
+                "multicast_id" => response.multicast_id = map.next_value()?,
+                "success" => response.success = map.next_value()?,
+                "failure" => response.failure = map.next_value()?,
+                "canonical_ids" => response.canonical_ids = map.next_value()?,
+                "results" => map.next_value_seed(ResultsSeed(&mut self.on_result))?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+struct ResultsSeed<'f, F: 'f>(&'f mut F);
+
+impl<'de, 'f, F> DeserializeSeed<'de> for ResultsSeed<'f, F>
+where
+    F: FnMut(MessageResult),
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de, 'f, F> Visitor<'de> for ResultsSeed<'f, F>
+where
+    F: FnMut(MessageResult),
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of message results")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while let Some(result) = seq.next_element::<MessageResult>()? {
+            (self.0)(result);
+        }
+        Ok(())
+    }
+}
+
+/// A response body as understood by `GcmResponse::parse_lenient`, one variant
+/// per accepted envelope.
+#[derive(Debug)]
+pub enum LenientResponse {
+    Multicast(GcmResponse),
+    Results(Vec<MessageResult>),
+    Single(MessageResult),
+}
+
+impl LenientResponse {
+    /// The per message results, whatever the envelope was.
+    pub fn into_results(self) -> Vec<MessageResult> {
+        match self {
+            LenientResponse::Multicast(response) => response.results.unwrap_or_default(),
+            LenientResponse::Results(results) => results,
+            LenientResponse::Single(result) => vec![result],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct MessageResult {
     pub message_id: Option<String>,
     pub registration_id: Option<u64>,
     pub error: Option<String>,
+    /// Human readable explanation of `error`, only some responses have one.
+    pub error_description: Option<String>,
+}
+
+impl MessageResult {
+    /// `error` as an `ErrorReason`, `None` if the message was accepted.
+    pub fn reason(&self) -> Option<ErrorReason> {
+        self.error.as_ref().map(|error| ErrorReason::from(error.as_str()))
+    }
+}
+
+/// Why GCM did not accept a message for a registration id, the `error` of a
+/// `MessageResult`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ErrorReason {
+    MissingRegistration,
+    InvalidRegistration,
+    /// The app was uninstalled or the token expired, drop the token.
+    NotRegistered,
+    InvalidPackageName,
+    MismatchSenderId,
+    MessageTooBig,
+    InvalidDataKey,
+    InvalidTtl,
+    Unavailable,
+    InternalServerError,
+    DeviceMessageRateExceeded,
+    TopicsMessageRateExceeded,
+    /// An error this crate does not know of.
+    Other(String),
+}
+
+impl ErrorReason {
+    /// Whether sending to the registration id again is pointless. Errors this
+    /// crate does not know of count as permanent.
+    pub fn is_permanent(&self) -> bool {
+        !matches!(
+            *self,
+            ErrorReason::Unavailable
+                | ErrorReason::InternalServerError
+                | ErrorReason::DeviceMessageRateExceeded
+                | ErrorReason::TopicsMessageRateExceeded
+        )
+    }
 }
 
-#[derive(PartialEq, Debug)]
+impl<'a> From<&'a str> for ErrorReason {
+    fn from(error: &'a str) -> ErrorReason {
+        match error {
+            "MissingRegistration" => ErrorReason::MissingRegistration,
+            "InvalidRegistration" => ErrorReason::InvalidRegistration,
+            "NotRegistered" => ErrorReason::NotRegistered,
+            "InvalidPackageName" => ErrorReason::InvalidPackageName,
+            "MismatchSenderId" => ErrorReason::MismatchSenderId,
+            "MessageTooBig" => ErrorReason::MessageTooBig,
+            "InvalidDataKey" => ErrorReason::InvalidDataKey,
+            "InvalidTtl" => ErrorReason::InvalidTtl,
+            "Unavailable" => ErrorReason::Unavailable,
+            "InternalServerError" => ErrorReason::InternalServerError,
+            "DeviceMessageRateExceeded" => ErrorReason::DeviceMessageRateExceeded,
+            "TopicsMessageRateExceeded" => ErrorReason::TopicsMessageRateExceeded,
+            other => ErrorReason::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum GcmError {
     Unauthorized,
     InvalidMessage(String),
     ServerError,
     InvalidJsonBody,
+    /// A proxy or load balancer in front of GCM answered 502, 503 or 504.
+    Gateway { status: u16 },
+    /// GCM refused the request as malformed and said why, e.g.
+    /// `InvalidParameters: time_to_live must be at most 2419200`.
+    InvalidRequest(String),
+    /// Registration ids GCM permanently refused, see `GcmSender::send_strict`.
+    PermanentFailures(Vec<(String, ErrorReason)>),
+    /// The `SendQueue` taking the message has no workers left to send it.
+    QueueClosed,
+}
+
+impl GcmError {
+    /// Whether sending the same request again may succeed: server side and
+    /// gateway errors are retryable, rejected requests are not.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            GcmError::ServerError | GcmError::Gateway { .. } => true,
+            GcmError::Unauthorized
+            | GcmError::InvalidMessage(_)
+            | GcmError::InvalidRequest(_)
+            | GcmError::InvalidJsonBody
+            | GcmError::PermanentFailures(_)
+            | GcmError::QueueClosed => false,
+        }
+    }
 }
 
 impl Display for GcmError {
@@ -75,7 +486,13 @@ impl Display for GcmError {
             GcmError::Unauthorized => write!(f, "UnauthorizedError"),
             GcmError::ServerError => write!(f, "ServerError"),
             GcmError::InvalidMessage(ref message) => write!(f, "InvalidMessage: {}", message),
+            GcmError::InvalidRequest(ref reason) => write!(f, "InvalidRequest: {}", reason),
             GcmError::InvalidJsonBody => write!(f, "InvalidJsonBody"),
+            GcmError::Gateway { status } => write!(f, "GatewayError: {}", status),
+            GcmError::PermanentFailures(ref failures) => {
+                write!(f, "PermanentFailures: {} registration ids", failures.len())
+            }
+            GcmError::QueueClosed => write!(f, "QueueClosed"),
         }
     }
 }
@@ -86,7 +503,11 @@ impl error::Error for GcmError {
             GcmError::Unauthorized => "UnauthorizedError",
             GcmError::ServerError => "ServerError",
             GcmError::InvalidMessage(_) => "InvalidMessage",
+            GcmError::InvalidRequest(_) => "InvalidRequest",
             GcmError::InvalidJsonBody => "InvalidJsonBody",
+            GcmError::Gateway { .. } => "GatewayError",
+            GcmError::PermanentFailures(_) => "PermanentFailures",
+            GcmError::QueueClosed => "QueueClosed",
         }
     }
 }
@@ -1,99 +1,875 @@
-use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use hyper::client::Response;
-use hyper::header;
-use hyper::header::Headers;
-use hyper::mime::{Attr, Mime, SubLevel, TopLevel, Value};
-use hyper::net::HttpsConnector;
-use hyper::status::{StatusClass, StatusCode};
-use hyper::Client;
-use hyper_native_tls::NativeTlsClient;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde_json::{Map, Value as JsonValue};
 
 use gcm_util;
-use message::response::{GcmError, GcmResponse};
-use message::Message;
+use message::outcome::{AttemptRecord, Correlation, DryRunSummary, RetryHistory, SendOutcome, Timings};
+use message::response::{ErrorReason, GcmError, GcmResponse, MessageResult};
+use message::telemetry;
+use message::transport::{HttpTransport, SendTransport, TransportResponse};
+use message::validate::MAX_REGISTRATION_IDS;
+use message::{DataValuePolicy, Message, Priority};
 
 type GcmResult = Result<GcmResponse, GcmError>;
 
-#[allow(dead_code)]
+/// A function applied to the serialized message body right before it is sent.
+pub type BodyTransform = Box<dyn Fn(JsonValue) -> JsonValue + Send + Sync>;
+
+/// Decides whether a failed request is worth retrying.
+pub type RetryablePredicate = Box<dyn Fn(&GcmError) -> bool + Send + Sync>;
+
+/// Casing of the top-level keys of posted messages.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum KeyCase {
+    /// `registration_ids`, `time_to_live`, ... as GCM expects them.
+    #[default]
+    Snake,
+    /// `registrationIds`, `timeToLive`, ... for proxies translating to GCM.
+    Camel,
+}
+
+/// The per-token error GCM answers when a device gets messages too fast.
+const DEVICE_RATE_EXCEEDED: &str = "DeviceMessageRateExceeded";
+
 pub struct GcmSender {
-    google_api: String,
-    api_key: String,
-    client: Client,
-    headers: Headers,
+    transport: Box<dyn SendTransport>,
+    body_transform: Option<BodyTransform>,
+    key_case: KeyCase,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    retryable: Option<RetryablePredicate>,
+    diagnostics: bool,
+    default_topic_priority: Option<Priority>,
+    downgrade_on_rate_limit: bool,
+    payload_hashing: bool,
+    recent_payloads: Option<RecentPayloads>,
 }
 
-impl GcmSender {
-    pub fn new(google_api: String, api_key: String) -> GcmSender {
-        let ssl = NativeTlsClient::new().unwrap();
-        let connector = HttpsConnector::new(ssl);
-        let client = Client::with_connector(connector);
+/// Hashes of the last `window` bodies sent, oldest first.
+struct RecentPayloads {
+    window: usize,
+    hashes: Mutex<VecDeque<u64>>,
+}
 
-        let mut headers = Headers::new();
-        let mime = Mime(
-            TopLevel::Application,
-            SubLevel::Json,
-            vec![(Attr::Charset, Value::Utf8)],
-        );
+impl RecentPayloads {
+    /// Remember `hash`, telling whether it was among the remembered ones already.
+    fn check(&self, hash: u64) -> bool {
+        let mut hashes = self.hashes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let seen = hashes.contains(&hash);
+        if hashes.len() == self.window {
+            hashes.pop_front();
+        }
+        hashes.push_back(hash);
+        seen
+    }
+}
 
-        headers.set(header::Authorization("key=".to_string() + &api_key));
-        headers.set(header::ContentType(mime));
+struct Delivery {
+    response: Result<TransportResponse, GcmError>,
+    attempts: u32,
+    history: Option<RetryHistory>,
+    /// Time spent in the transport, over all attempts.
+    network: Duration,
+}
 
+impl GcmSender {
+    pub fn new(google_api: String, api_key: String) -> GcmSender {
+        GcmSender::with_transport(Box::new(HttpTransport::new(google_api, api_key)))
+    }
+
+    /// Get a sender that hands message bodies to `transport` instead of posting
+    /// them to GCM over HTTP itself.
+    pub fn with_transport(transport: Box<dyn SendTransport>) -> GcmSender {
         GcmSender {
-            google_api,
-            api_key,
-            client,
-            headers,
+            transport,
+            body_transform: None,
+            key_case: KeyCase::Snake,
+            max_retries: 0,
+            retry_base_delay: Duration::from_secs(1),
+            retryable: None,
+            diagnostics: false,
+            default_topic_priority: None,
+            downgrade_on_rate_limit: false,
+            payload_hashing: false,
+            recent_payloads: None,
         }
     }
 
-    // Todo : Have to add retry logic here
+    /// Rename the top-level keys of every posted message. Only useful when the
+    /// endpoint is a proxy expecting camelCase; GCM itself wants the default
+    /// `KeyCase::Snake`. Nested objects such as `data` and `notification` are
+    /// left alone. Applied before any body transform.
+    pub fn with_key_case(mut self, key_case: KeyCase) -> GcmSender {
+        self.key_case = key_case;
+        self
+    }
+
+    /// Retry requests failing with a retryable error (see `GcmError::is_retryable`)
+    /// up to `max_retries` times. The wait before the n-th retry is
+    /// `base_delay * 2^(n-1)`, capped at a minute, unless GCM or a gateway asked
+    /// for a specific wait with a `Retry-After` header. No retries by default.
+    /// # Examples:
+    /// ```rust
+    /// use std::time::Duration;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_retries(3, Duration::from_millis(500));
+    /// ```
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> GcmSender {
+        self.max_retries = max_retries;
+        self.retry_base_delay = base_delay;
+        self
+    }
+
+    /// Post to `google_api` when the connection to the usual endpoint cannot
+    /// be made, e.g. when it is refused or the TLS handshake fails. Requests
+    /// that reached the usual endpoint are never sent again elsewhere, failed
+    /// or not; retrying them is up to `with_retries`. Both endpoints get the
+    /// same server key, so both must accept it. Has no effect on senders
+    /// built `with_transport` around a transport without endpoints.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_fallback_endpoint("https://gcm-http.googleapis.com/gcm/send".to_string());
+    /// ```
+    pub fn with_fallback_endpoint(mut self, google_api: String) -> GcmSender {
+        self.transport.set_fallback_endpoint(google_api);
+        self
+    }
+
+    /// Decide with `predicate` instead of `GcmError::is_retryable` which errors
+    /// `with_retries` retries, e.g. to give up on gateway errors right away or
+    /// to retry bad requests a flaky proxy produces now and then.
+    /// # Examples:
+    /// ```rust
+    /// use std::time::Duration;
+    /// use gcm::Error;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_retries(3, Duration::from_millis(500))
+    ///     .with_retryable_predicate(Box::new(|error| error.is_retryable() || *error == Error::InvalidJsonBody));
+    /// ```
+    pub fn with_retryable_predicate(mut self, predicate: RetryablePredicate) -> GcmSender {
+        self.retryable = Some(predicate);
+        self
+    }
+
+    /// Install a hook that is invoked on every serialized message body just
+    /// before it is posted, e.g. to inject audit fields or to mirror a redacted
+    /// copy to a log.
+    ///
+    /// The returned value is sent to GCM as-is: a transform that drops required
+    /// fields or produces something GCM does not understand will break the request.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_body_transform(Box::new(|mut body| {
+    ///         body["campaign"] = "spring-sale".into();
+    ///         body
+    ///     }));
+    /// ```
+    pub fn with_body_transform(mut self, transform: BodyTransform) -> GcmSender {
+        self.body_transform = Some(transform);
+        self
+    }
+
+    /// Send topic and condition messages without a priority of their own with
+    /// `priority` instead of GCM's default, normal, which can hold them back
+    /// on idle devices. Messages to registration ids or a single token, and
+    /// messages with an explicit priority, are sent as they are. Off by default.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::Priority;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_default_topic_priority(Priority::High);
+    /// ```
+    pub fn with_default_topic_priority(mut self, priority: Priority) -> GcmSender {
+        self.default_topic_priority = Some(priority);
+        self
+    }
+
+    /// Retry the registration ids failing with `DeviceMessageRateExceeded` in
+    /// a high priority send at `Priority::Normal`. High priority messages to a
+    /// throttled device only make the throttling worse; normal priority gets
+    /// through later, possibly much later on idle devices, trading immediacy
+    /// for delivery. Off by default.
+    ///
+    /// The first downgraded send is made even without `with_retries`. Any
+    /// further request, retrying a failed downgraded send or ids still
+    /// throttled, comes out of the `with_retries` budget left over by the
+    /// original send: one send makes at most `max_retries + 2` requests.
+    pub fn with_rate_limit_downgrade(mut self, enabled: bool) -> GcmSender {
+        self.downgrade_on_rate_limit = enabled;
+        self
+    }
+
+    /// Put the `gcm_util::payload_hash` of every posted body into its
+    /// `SendOutcome`, to spot identical sends across logs. Off by default.
+    pub fn with_payload_hashing(mut self, enabled: bool) -> GcmSender {
+        self.payload_hashing = enabled;
+        self
+    }
+
+    /// Remember the payload hashes of the last `window` sends and flag a send
+    /// repeating one of them with `SendOutcome::duplicate`, to catch code
+    /// sending the same message over and over. The send itself is not held
+    /// back. Implies `with_payload_hashing`; memory stays at `window` hashes.
+    /// A window of 0 turns detection off again.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::Message;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string())
+    ///     .with_duplicate_detection(100);
+    /// let outcome = sender.send_with_outcome(Message::new(vec!["<registration id>"]));
+    /// if outcome.duplicate {
+    ///     eprintln!("payload {:x} sent twice", outcome.payload_hash.unwrap());
+    /// }
+    /// ```
+    pub fn with_duplicate_detection(mut self, window: usize) -> GcmSender {
+        self.recent_payloads = if window == 0 {
+            None
+        } else {
+            self.payload_hashing = true;
+            Some(RecentPayloads {
+                window,
+                hashes: Mutex::new(VecDeque::with_capacity(window)),
+            })
+        };
+        self
+    }
+
     pub fn send(&self, msg: Message) -> GcmResult {
-        let parsed_msg = gcm_util::to_json(&msg)?;
-        let mut result = self.post(&parsed_msg)?;
-        self.parse_response(&mut result)
+        self.send_with_outcome(msg).result
+    }
+
+    /// Send `msg` like `send` does, additionally reporting how the request went.
+    pub fn send_with_outcome(&self, msg: Message) -> SendOutcome {
+        let correlation = Correlation {
+            operation_id: gcm_util::new_operation_id(),
+            index: 0,
+        };
+        self.send_correlated(msg, correlation)
     }
 
-    fn parse_response(&self, response: &mut Response) -> GcmResult {
-        let mut body = String::new();
-        let resp_code = response.status;
+    /// Send several messages as one logical operation, typically the parts of
+    /// one audience split by priority, package or platform (see `Message::split_by`).
+    /// Every outcome carries `operation_id`, messages are sent in order.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::{gcm_util, Message, Priority};
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// let messages = Message::new(vec!["token-1", "token-2"])
+    ///     .split_by(|token| token == "token-2")
+    ///     .into_iter()
+    ///     .map(|(urgent, message)| message.apply_if(urgent, |m| m.priority(Priority::High)))
+    ///     .collect();
+    ///
+    /// for outcome in sender.send_operation(&gcm_util::new_operation_id(), messages) {
+    ///     println!("{} #{}: {:?}", outcome.correlation.operation_id, outcome.correlation.index, outcome.result);
+    /// }
+    /// ```
+    pub fn send_operation(&self, operation_id: &str, msgs: Vec<Message>) -> Vec<SendOutcome> {
+        msgs.into_iter()
+            .enumerate()
+            .map(|(index, msg)| {
+                let correlation = Correlation {
+                    operation_id: operation_id.to_string(),
+                    index,
+                };
+                self.send_correlated(msg, correlation)
+            })
+            .collect()
+    }
 
-        let response_result = response.read_to_string(&mut body);
-        match response_result {
-            Ok(_) => self.parse_gcm_result(resp_code, &body),
-            Err(_) => self.parse_gcm_result(StatusCode::InternalServerError, "Server Error"),
+    /// Send `base` to every id in `ids`, each with its data extended by
+    /// `per_token(id)`, e.g. to greet every recipient by name. Personal values
+    /// override base values of the same key; values that are not strings are
+    /// sent as their JSON text, like `DataValuePolicy::Stringify` does.
+    ///
+    /// GCM sends one payload per request, but not one request per id is
+    /// needed: ids ending up with identical data are grouped into one
+    /// multicast (split at 1000 ids), so a campaign with a handful of variants
+    /// costs a handful of requests. Groups are sent in the order their first
+    /// id appears, as one operation (see `send_operation`).
+    /// # Examples:
+    /// ```rust,no_run
+    /// extern crate gcm;
+    /// extern crate serde_json;
+    ///
+    /// use gcm::Message;
+    /// use gcm::sender::GcmSender;
+    /// use serde_json::{Map, Value};
+    ///
+    /// # fn main() {
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// let ids = vec!["token-1".to_string(), "token-2".to_string()];
+    ///
+    /// let outcomes = sender.send_personalized(Message::new(vec![]), ids, |token| {
+    ///     let mut data = Map::new();
+    ///     data.insert("greeting".to_string(), Value::from(format!("Hi {}", token)));
+    ///     data
+    /// });
+    /// # }
+    /// ```
+    pub fn send_personalized<F>(&self, base: Message, ids: Vec<String>, per_token: F) -> Vec<SendOutcome>
+    where
+        F: Fn(&str) -> Map<String, JsonValue>,
+    {
+        // serde_json's Map is sorted, so equal data serializes the same
+        let mut groups: Vec<(Map<String, JsonValue>, Vec<String>)> = Vec::new();
+        let mut group_of: HashMap<String, usize> = HashMap::new();
+        for id in ids {
+            let data = per_token(&id);
+            let key = JsonValue::Object(data.clone()).to_string();
+            let group = *group_of.entry(key).or_insert_with(|| {
+                groups.push((data, Vec::new()));
+                groups.len() - 1
+            });
+            groups[group].1.push(id);
         }
+
+        let mut messages = Vec::new();
+        for (data, ids) in groups {
+            let personal = Message::new(vec![])
+                .data_values(&data, DataValuePolicy::Stringify)
+                .unwrap_or_else(|_| unreachable!("stringified data values are never rejected"));
+            let message = base.clone().merge(personal);
+            for batch in ids.chunks(MAX_REGISTRATION_IDS) {
+                messages.push(message.clone_with_ids(batch.to_vec()));
+            }
+        }
+
+        self.send_operation(&gcm_util::new_operation_id(), messages)
     }
 
-    fn post(&self, json_request: &String) -> Result<Response, GcmError> {
-        let response = self
-            .client
-            .post(&self.google_api)
-            .body(json_request.as_bytes())
-            .headers(self.headers.clone())
-            .send();
+    /// Capture extra detail about every send in its `SendOutcome`, such as the
+    /// `RetryHistory` and `Timings`. Off by default to keep sends cheap.
+    pub fn with_diagnostics(mut self, enabled: bool) -> GcmSender {
+        self.diagnostics = enabled;
+        self
+    }
+
+    fn send_correlated(&self, msg: Message, correlation: Correlation) -> SendOutcome {
+        let started = Instant::now();
+        let mut attempts = 0;
+        let mut retry_history = None;
+        let mut timings = None;
+        let mut payload_hash = None;
+        let mut duplicate = false;
+        let result = telemetry::traced(&msg, || {
+            let serializing = Instant::now();
+            let parsed_msg = self.build_body(&msg)?;
+            let serialization = serializing.elapsed();
+            if self.payload_hashing {
+                let hash = gcm_util::payload_hash(&parsed_msg);
+                payload_hash = Some(hash);
+                duplicate = self.recent_payloads.as_ref().is_some_and(|recent| recent.check(hash));
+            }
+            let delivery = self.deliver(&parsed_msg, self.max_retries);
+            attempts = delivery.attempts;
+            retry_history = delivery.history;
+            if self.diagnostics {
+                timings = Some(Timings {
+                    serialization,
+                    network: delivery.network,
+                });
+            }
+            let response = parse_body(&delivery.response?.body)?;
+            if self.downgrade_on_rate_limit {
+                let mut network = Duration::from_secs(0);
+                let response = self.downgrade_rate_limited(&msg, response, &mut attempts, &mut network);
+                if let Some(ref mut timings) = timings {
+                    timings.network += network;
+                }
+                Ok(response)
+            } else {
+                Ok(response)
+            }
+        });
 
-        match response {
-            Ok(response) => Ok(response),
-            Err(_) => Err(GcmError::ServerError),
+        SendOutcome {
+            correlation,
+            result,
+            attempts,
+            latency: started.elapsed(),
+            retry_history,
+            timings,
+            payload_hash,
+            duplicate,
         }
     }
 
-    fn parse_gcm_result(&self, status: StatusCode, body: &str) -> GcmResult {
-        //200 Ok: Request was successful!
-        if status == StatusCode::Ok {
-            return serde_json::from_str(body).or_else(|_| Err(GcmError::InvalidJsonBody));
+    /// Send `msg` and call `on_result` with every registration id and its
+    /// `MessageResult` while the response is being parsed, in the order the ids
+    /// were given. The results are not collected, so `results` of the returned
+    /// response is `None`.
+    pub fn send_with_callback_per_token<F>(&self, msg: Message, mut on_result: F) -> GcmResult
+    where
+        F: FnMut(&str, &MessageResult),
+    {
+        telemetry::traced(&msg, || {
+            let ids = msg.registration_ids.clone().unwrap_or_default();
+            let parsed_msg = self.build_body(&msg)?;
+            let response = self.deliver(&parsed_msg, self.max_retries).response?;
+
+            let mut index = 0;
+            GcmResponse::parse_streaming(&response.body, |result| {
+                if let Some(id) = ids.get(index) {
+                    on_result(id, &result);
+                }
+                index += 1;
+            })
+        })
+    }
+
+    /// Send `msg` to `ids` all or nothing: if GCM permanently refuses any of
+    /// them (see `ErrorReason::is_permanent`), fail with
+    /// `GcmError::PermanentFailures` listing those ids instead of returning a
+    /// partial success, e.g. to abort a transactional campaign. The message
+    /// is still delivered to the other ids. The request is retried like
+    /// `send` does, and ids failing with a retryable error, such as
+    /// `Unavailable`, do not fail the send but are left in the response to
+    /// retry. `ids` go out in one request, so at most 1000 of them.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::{Error, Message};
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// let ids = vec!["token-1".to_string(), "token-2".to_string()];
+    /// if let Err(Error::PermanentFailures(failures)) = sender.send_strict(Message::new(vec![]), ids) {
+    ///     for (id, reason) in failures {
+    ///         println!("{} refused: {:?}", id, reason);
+    ///     }
+    /// }
+    /// ```
+    pub fn send_strict(&self, msg: Message, ids: Vec<String>) -> GcmResult {
+        let response = self.send(msg.clone_with_ids(ids.clone()))?;
+
+        let failures: Vec<(String, ErrorReason)> = ids
+            .into_iter()
+            .zip(response.results.iter().flatten())
+            .filter_map(|(id, result)| match result.reason() {
+                Some(reason) if reason.is_permanent() => Some((id, reason)),
+                _ => None,
+            })
+            .collect();
+        if failures.is_empty() {
+            Ok(response)
+        } else {
+            Err(GcmError::PermanentFailures(failures))
         }
+    }
+
+    /// Send `msg` and only report how many registration ids it was delivered to
+    /// and how many failed, as `(success, failure)`. The per-token results are
+    /// counted while parsing and never collected.
+    pub fn send_count(&self, msg: Message) -> Result<(usize, usize), GcmError> {
+        let mut success = 0;
+        let mut failure = 0;
+        let response = self.send_with_callback_per_token(msg, |_, result| {
+            if result.error.is_some() {
+                failure += 1;
+            } else {
+                success += 1;
+            }
+        })?;
+
+        Ok((
+            response.success.map_or(success, |count| count as usize),
+            response.failure.map_or(failure, |count| count as usize),
+        ))
+    }
+
+    /// Send `msg` retrying like `send`, but never to a registration id GCM
+    /// already confirmed.
+    ///
+    /// A retry after a timeout or a connection dropped mid-response is a
+    /// gamble: GCM may well have delivered the message, and plain `send`
+    /// delivers it again, a duplicate on every device. Here the results read
+    /// before a failure are kept, and every retry only goes to the ids without
+    /// a result yet; ids GCM already answered for, delivered or refused, are
+    /// not sent to again. The returned response has one result per id of
+    /// `msg`, in order, whichever attempt they came from; `multicast_id` is the
+    /// last one. Ids whose outcome never arrived may still have been reached,
+    /// only an id with a result is known for sure.
+    ///
+    /// Messages addressed with `to` or `condition` have no ids to narrow down
+    /// and are sent with `send`.
+    pub fn send_idempotent(&self, msg: Message) -> GcmResult {
+        let ids = match msg.registration_ids {
+            Some(ref ids) if msg.to.is_none() && msg.condition.is_none() => ids.clone(),
+            _ => return self.send(msg),
+        };
+
+        telemetry::traced(&msg, || self.send_until_answered(&msg, &ids))
+    }
 
-        //check for server error (5xx)
-        if status.class() == StatusClass::ServerError {
-            return Err(GcmError::ServerError);
+    // the attempts of `send_idempotent` to `ids`, the registration ids of `msg`
+    fn send_until_answered(&self, msg: &Message, ids: &[String]) -> GcmResult {
+        let mut results: Vec<Option<MessageResult>> = vec![None; ids.len()];
+        let mut pending: Vec<usize> = (0..ids.len()).collect();
+        let mut multicast_id = None;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let mut batch = msg.clone();
+            batch.registration_ids = Some(pending.iter().map(|&i| ids[i].clone()).collect());
+            let body = self.build_body(&batch)?;
+
+            let (error, retry_after) = match self.transport.post(&body) {
+                Ok(response) => {
+                    // a response cut short still holds the results before the cut
+                    let mut read = Vec::new();
+                    let parsed = GcmResponse::parse_streaming(&response.body, |result| read.push(result));
+                    for (&i, result) in pending.iter().zip(read) {
+                        results[i] = Some(result);
+                    }
+
+                    match parsed {
+                        Ok(ref parsed) if response.status == 200 => {
+                            multicast_id = parsed.multicast_id.or(multicast_id);
+                            break;
+                        }
+                        _ if response.status == 200 => (GcmError::InvalidJsonBody, None),
+                        _ => (
+                            gcm_util::status_error(response.status, &response.body),
+                            response.retry_after,
+                        ),
+                    }
+                }
+                Err(error) => (error, None),
+            };
+
+            // every result is final, only ids without one are tried again
+            pending.retain(|&i| results[i].is_none());
+            if pending.is_empty() {
+                break;
+            }
+            if attempts > self.max_retries || !self.is_retryable(&error) {
+                return Err(error);
+            }
+            thread::sleep(self.retry_delay(attempts, retry_after));
         }
-        //match remaining status codes
-        match status {
-            StatusCode::Unauthorized => Err(GcmError::Unauthorized),
-            StatusCode::BadRequest => Err(GcmError::InvalidMessage(body.to_string())),
-            _ => Err(GcmError::InvalidMessage("Unknown Error".to_string())),
+
+        let results: Vec<MessageResult> = results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or(MessageResult {
+                    message_id: None,
+                    registration_id: None,
+                    error: Some("Unavailable".to_string()),
+                    error_description: None,
+                })
+            })
+            .collect();
+        let mut response = GcmResponse {
+            multicast_id,
+            results: Some(results),
+            ..GcmResponse::default()
+        };
+        response.recount();
+        Ok(response)
+    }
+
+    /// Prepare the transport for a burst of sends, so the first of them does not
+    /// pay for connecting and the TLS handshake. Over HTTP this opens a
+    /// connection to the endpoint and keeps it around for sending.
+    ///
+    /// From then on the sender keeps connections alive instead of connecting
+    /// for every request. An idle connection the endpoint has closed in the
+    /// meantime fails the request it is reused for, so pair this with
+    /// `with_retries`.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::Message;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// sender.warm_up().unwrap();
+    ///
+    /// let result = sender.send(Message::new(vec!["<registration id>"]));
+    /// ```
+    pub fn warm_up(&self) -> Result<(), GcmError> {
+        self.transport.warm_up()
+    }
+
+    /// Drop the sender, and with it the connections its transport keeps. This
+    /// is no more than dropping it, spelled out where the shutdown should be
+    /// visible; nothing is waited for.
+    pub fn close(self) {
+        drop(self.transport);
+    }
+
+    /// Dry run `msg` to a random `fraction` (0 to 1) of its registration ids
+    /// before sending to all of them, to catch systemic problems such as a
+    /// wrong package or a stale token export early. At least one and at most
+    /// 1000 ids are sampled.
+    /// # Examples:
+    /// ```rust,no_run
+    /// use gcm::Message;
+    /// use gcm::sender::GcmSender;
+    ///
+    /// let sender = GcmSender::new("https://fcm.googleapis.com/fcm/send".to_string(), "<api-key>".to_string());
+    /// let audience = vec!["token-1", "token-2", "token-3"];
+    ///
+    /// let summary = sender.dry_run_sample(Message::new(audience.clone()), 0.01).unwrap();
+    /// if summary.failure_rate() < 0.2 {
+    ///     sender.send(Message::new(audience)).unwrap();
+    /// }
+    /// ```
+    pub fn dry_run_sample(&self, msg: Message, fraction: f64) -> Result<DryRunSummary, GcmError> {
+        self.dry_run_sample_seeded(msg, fraction, gcm_util::unique_seed())
+    }
+
+    /// `dry_run_sample` picking the sample from `seed`, the same seed always
+    /// picks the same ids.
+    pub fn dry_run_sample_seeded(
+        &self,
+        msg: Message,
+        fraction: f64,
+        seed: u64,
+    ) -> Result<DryRunSummary, GcmError> {
+        let mut ids = msg.registration_ids.clone().unwrap_or_default();
+        if ids.is_empty() {
+            return Ok(DryRunSummary::default());
         }
+
+        let wanted = (ids.len() as f64 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+        let sample_size = wanted.max(1).min(ids.len()).min(MAX_REGISTRATION_IDS);
+
+        // partial Fisher-Yates: the first `sample_size` ids end up a uniform sample
+        let mut rng = gcm_util::SplitMix64::new(seed);
+        for i in 0..sample_size {
+            let j = i + rng.below(ids.len() - i);
+            ids.swap(i, j);
+        }
+        ids.truncate(sample_size);
+
+        let mut sample = msg.dry_run(true);
+        sample.registration_ids = Some(ids);
+
+        let mut summary = DryRunSummary {
+            sampled: sample_size,
+            ..DryRunSummary::default()
+        };
+        self.send_with_callback_per_token(sample, |_, result| match result.error {
+            Some(ref error) => {
+                summary.failure += 1;
+                *summary.errors.entry(error.clone()).or_insert(0) += 1;
+            }
+            None => summary.success += 1,
+        })?;
+
+        Ok(summary)
     }
+
+    // resend to rate limited ids at normal priority, see `with_rate_limit_downgrade`
+    fn downgrade_rate_limited(
+        &self,
+        msg: &Message,
+        mut response: GcmResponse,
+        attempts: &mut u32,
+        network: &mut Duration,
+    ) -> GcmResponse {
+        let ids = match msg.registration_ids {
+            Some(ref ids) if msg.priority == Some(Priority::High) => ids,
+            _ => return response,
+        };
+
+        // the retries the original send left unused, shared by all rounds
+        let mut retries_left = self.max_retries.saturating_sub(*attempts - 1);
+        for round in 1.. {
+            let limited: Vec<usize> = match response.results {
+                Some(ref results) => results
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, result)| result.error.as_deref() == Some(DEVICE_RATE_EXCEEDED))
+                    .map(|(i, _)| i)
+                    .collect(),
+                None => return response,
+            };
+            if limited.is_empty() {
+                break;
+            }
+            // only the first round is on the house
+            if round > 1 {
+                if retries_left == 0 {
+                    break;
+                }
+                retries_left -= 1;
+            }
+
+            thread::sleep(self.retry_delay(round, None));
+            let mut downgraded = msg.clone().priority(Priority::Normal);
+            downgraded.registration_ids = Some(limited.iter().map(|&i| ids[i].clone()).collect());
+            let body = match self.build_body(&downgraded) {
+                Ok(body) => body,
+                Err(_) => break,
+            };
+            let delivery = self.deliver(&body, retries_left);
+            *attempts += delivery.attempts;
+            *network += delivery.network;
+            retries_left -= delivery.attempts - 1;
+            let retried = match delivery.response.and_then(|r| parse_body(&r.body)) {
+                Ok(GcmResponse {
+                    results: Some(results),
+                    ..
+                }) => results,
+                _ => break,
+            };
+
+            if let Some(ref mut results) = response.results {
+                for (&i, result) in limited.iter().zip(retried) {
+                    results[i] = result;
+                }
+            }
+            response.recount();
+        }
+
+        response
+    }
+
+    fn build_body(&self, msg: &Message) -> Result<String, GcmError> {
+        let defaulted;
+        let msg = match self.default_topic_priority {
+            Some(priority)
+                if msg.priority.is_none() && (msg.to_is_topic() || msg.condition.is_some()) =>
+            {
+                defaulted = msg.clone().priority(priority);
+                &defaulted
+            }
+            _ => msg,
+        };
+
+        if self.key_case == KeyCase::Snake && self.body_transform.is_none() {
+            return gcm_util::to_json(msg);
+        }
+
+        let mut body = gcm_util::to_json_value(msg)?;
+        if self.key_case == KeyCase::Camel {
+            body = camel_case_keys(body);
+        }
+        if let Some(ref transform) = self.body_transform {
+            body = transform(body);
+        }
+        serde_json::to_string(&body).map_err(|_| GcmError::InvalidJsonBody)
+    }
+
+    /// Post `body`, retrying retryable failures up to `max_retries` times.
+    /// Only 200 answers end up in `Delivery::response` as `Ok`.
+    fn deliver(&self, body: &str, max_retries: u32) -> Delivery {
+        let mut attempts = 0;
+        let mut history = if self.diagnostics {
+            Some(RetryHistory::default())
+        } else {
+            None
+        };
+        let mut delay = Duration::from_secs(0);
+        let mut network = Duration::from_secs(0);
+
+        loop {
+            attempts += 1;
+            let posted = Instant::now();
+            let response = self.transport.post(body);
+            network += posted.elapsed();
+            if let Some(ref mut history) = history {
+                history.attempts.push(AttemptRecord {
+                    status: response.as_ref().ok().map(|response| response.status),
+                    delay,
+                    error: match response {
+                        Ok(ref response) if response.status == 200 => None,
+                        Ok(ref response) => Some(gcm_util::status_error(response.status, &response.body)),
+                        Err(ref error) => Some(error.clone()),
+                    },
+                });
+            }
+
+            let (error, retry_after) = match response {
+                Ok(response) => {
+                    if response.status == 200 {
+                        return Delivery {
+                            response: Ok(response),
+                            attempts,
+                            history,
+                            network,
+                        };
+                    }
+                    (
+                        gcm_util::status_error(response.status, &response.body),
+                        response.retry_after,
+                    )
+                }
+                Err(error) => (error, None),
+            };
+
+            if attempts > max_retries || !self.is_retryable(&error) {
+                return Delivery {
+                    response: Err(error),
+                    attempts,
+                    history,
+                    network,
+                };
+            }
+            delay = self.retry_delay(attempts, retry_after);
+            thread::sleep(delay);
+        }
+    }
+
+    fn is_retryable(&self, error: &GcmError) -> bool {
+        match self.retryable {
+            Some(ref predicate) => predicate(error),
+            None => error.is_retryable(),
+        }
+    }
+
+    fn retry_delay(&self, attempt: u32, retry_after: Option<u64>) -> Duration {
+        gcm_util::retry_delay(self.retry_base_delay, attempt, retry_after)
+    }
+}
+
+fn camel_case_keys(body: JsonValue) -> JsonValue {
+    match body {
+        JsonValue::Object(fields) => JsonValue::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (camel_case(&key), value))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn camel_case(key: &str) -> String {
+    let mut parts = key.split('_');
+    let mut camel = parts.next().unwrap_or("").to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+fn parse_body(body: &str) -> GcmResult {
+    serde_json::from_str(body).map_err(|_| GcmError::InvalidJsonBody)
 }
@@ -0,0 +1,124 @@
+//! Spans for sends, enabled with the `opentelemetry` feature. Attributes follow
+//! the OpenTelemetry messaging semantic conventions; registration ids are never
+//! recorded.
+//!
+//! Every send of `GcmSender` and `AsyncGsmSender` gets one span, retries
+//! included; `send_from_provider` gets one per request. Requests of a
+//! `SendQueue` are not traced.
+
+#[cfg(feature = "opentelemetry")]
+use futures::Future;
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::global::{self, BoxedSpan};
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::trace::{Span, SpanKind, Status, Tracer};
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::KeyValue;
+
+use message::async_sender::GcmResponseFuture;
+#[cfg(feature = "opentelemetry")]
+use message::outcome;
+use message::response::{GcmError, GcmResponse};
+use message::Message;
+
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn traced<F>(msg: &Message, send: F) -> Result<GcmResponse, GcmError>
+where
+    F: FnOnce() -> Result<GcmResponse, GcmError>,
+{
+    let (destination, token_count) = destination(msg);
+    let mut span = start(destination, token_count);
+    let result = send();
+    end(&mut span, &result);
+    result
+}
+
+/// Like `traced`, for a send that is still to be run.
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn traced_future(msg: &Message, send: GcmResponseFuture) -> GcmResponseFuture {
+    let (destination, token_count) = destination(msg);
+    end_with(start(destination, token_count), send)
+}
+
+/// Like `traced_future`, for a batch of `token_count` registration ids sent
+/// apart from the message they came with.
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn traced_batch(token_count: usize, send: GcmResponseFuture) -> GcmResponseFuture {
+    end_with(start("registration_ids", token_count), send)
+}
+
+// topics are no secret, registration ids are
+#[cfg(feature = "opentelemetry")]
+fn destination<'a>(msg: &Message<'a>) -> (&'a str, usize) {
+    match (msg.to, msg.condition) {
+        (Some(to), _) if to.starts_with("/topics/") => (to, 1),
+        (Some(_), _) => ("token", 1),
+        (None, Some(_)) => ("condition", 1),
+        (None, None) => (
+            "registration_ids",
+            msg.registration_ids.as_ref().map_or(0, Vec::len),
+        ),
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+fn start(destination: &str, token_count: usize) -> BoxedSpan {
+    let tracer = global::tracer("gcm");
+    tracer
+        .span_builder(format!("send {}", destination))
+        .with_kind(SpanKind::Producer)
+        .with_attributes(vec![
+            KeyValue::new("messaging.system", "gcm"),
+            KeyValue::new("messaging.operation.type", "send"),
+            KeyValue::new("messaging.destination.name", destination.to_string()),
+            KeyValue::new("messaging.batch.message_count", token_count as i64),
+        ])
+        .start(&tracer)
+}
+
+#[cfg(feature = "opentelemetry")]
+fn end(span: &mut BoxedSpan, result: &Result<GcmResponse, GcmError>) {
+    match *result {
+        Ok(ref response) => {
+            if let Some(multicast_id) = response.multicast_id {
+                span.set_attribute(KeyValue::new("messaging.message.id", multicast_id.to_string()));
+            }
+            span.set_attribute(KeyValue::new("gcm.success", response.success_count() as i64));
+            span.set_attribute(KeyValue::new("gcm.failure", response.failure_count() as i64));
+            span.set_status(Status::Ok);
+        }
+        Err(ref err) => {
+            // the error text may quote the response body, and with it tokens
+            let kind = outcome::error_kind(err);
+            span.set_attribute(KeyValue::new("error.type", kind));
+            span.set_status(Status::error(kind));
+        }
+    }
+    span.end();
+}
+
+#[cfg(feature = "opentelemetry")]
+fn end_with(mut span: BoxedSpan, send: GcmResponseFuture) -> GcmResponseFuture {
+    Box::new(send.then(move |result| {
+        end(&mut span, &result);
+        result
+    }))
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+pub(crate) fn traced<F>(_msg: &Message, send: F) -> Result<GcmResponse, GcmError>
+where
+    F: FnOnce() -> Result<GcmResponse, GcmError>,
+{
+    send()
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+pub(crate) fn traced_future(_msg: &Message, send: GcmResponseFuture) -> GcmResponseFuture {
+    send
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+pub(crate) fn traced_batch(_token_count: usize, send: GcmResponseFuture) -> GcmResponseFuture {
+    send
+}
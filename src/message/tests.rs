@@ -0,0 +1,813 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use serde_json;
+
+use gcm_util;
+
+use {
+    Advisory, DataValuePolicy, Error, GcmResponse, LenientResponse, Message, NotificationBuilder,
+    Partitioned, Priority, Severity,
+};
+
+const MULTICAST_BODY: &str = r#"{
+    "multicast_id": 216,
+    "success": 3,
+    "failure": 2,
+    "canonical_ids": 1,
+    "results": [
+        {"message_id": "1:0408"},
+        {"error": "Unavailable"},
+        {"error": "InvalidRegistration"},
+        {"message_id": "1:1516"},
+        {"message_id": "1:2342", "registration_id": 32}
+    ]
+}"#;
+
+#[test]
+fn should_stream_results_without_collecting() {
+    let mut seen = Vec::new();
+    let response = GcmResponse::parse_streaming(MULTICAST_BODY, |result| seen.push(result)).unwrap();
+
+    assert_eq!(response.multicast_id, Some(216));
+    assert_eq!(response.canonical_ids, Some(1));
+    assert!(response.results.is_none());
+    assert_eq!(seen.len() as u64, response.success.unwrap() + response.failure.unwrap());
+    assert_eq!(seen.iter().filter(|r| r.error.is_some()).count() as u64, response.failure.unwrap());
+    assert_eq!(seen[4].registration_id, Some(32));
+}
+
+#[test]
+fn should_reject_invalid_streaming_body() {
+    assert!(GcmResponse::parse_streaming(r#"{"results": 3}"#, |_| ()).is_err());
+    assert!(GcmResponse::parse_streaming(r#"{"success": 1} trailing"#, |_| ()).is_err());
+}
+
+#[test]
+fn should_reject_non_string_data_values_by_default() {
+    let data = json!({"title": "hi", "count": 3});
+    let result = Message::new(vec!["token"]).data_values(data.as_object().unwrap(), DataValuePolicy::default());
+
+    assert_eq!(
+        result.err(),
+        Some(Error::InvalidMessage("data value for key `count` is not a string".to_string()))
+    );
+
+    let data = json!({"urgent": false});
+    let result = Message::new(vec!["token"]).data_values(data.as_object().unwrap(), DataValuePolicy::Reject);
+    assert!(result.is_err());
+}
+
+#[test]
+fn should_stringify_non_string_data_values() {
+    let data = json!({"title": "hi", "count": 3, "ratio": 0.5, "urgent": true});
+    let message = Message::new(vec!["token"])
+        .data_values(data.as_object().unwrap(), DataValuePolicy::Stringify)
+        .unwrap();
+
+    let json = serde_json::to_value(&message).unwrap();
+    assert_eq!(
+        json["data"],
+        json!({"title": "hi", "count": "3", "ratio": "0.5", "urgent": "true"})
+    );
+}
+
+#[test]
+fn should_serialize_priority_lowercase() {
+    let message = Message::new(vec!["token"]).priority(Priority::High);
+    assert_eq!(serde_json::to_value(&message).unwrap()["priority"], "high");
+
+    let message = Message::new(vec!["token"]).priority(Priority::Normal);
+    assert_eq!(serde_json::to_value(&message).unwrap()["priority"], "normal");
+}
+
+#[test]
+fn should_parse_priority_in_any_casing() {
+    for input in &["HIGH", "High", "high"] {
+        let priority: Priority = serde_json::from_str(&format!("\"{}\"", input)).unwrap();
+        assert_eq!(priority, Priority::High);
+        assert_eq!(input.parse::<Priority>().unwrap(), Priority::High);
+
+        let message = Message::new(vec!["token"]).priority(priority);
+        assert_eq!(serde_json::to_value(&message).unwrap()["priority"], "high");
+    }
+
+    assert_eq!("NORMAL".parse::<Priority>().unwrap(), Priority::Normal);
+    assert!("urgent".parse::<Priority>().is_err());
+    assert!(serde_json::from_str::<Priority>("\"urgent\"").is_err());
+}
+
+#[test]
+fn should_merge_override_into_base() {
+    let mut base_data = HashMap::new();
+    base_data.insert("campaign", "spring");
+    base_data.insert("locale", "en");
+    let base = Message::new(vec!["a", "b"])
+        .collapse_key("promo")
+        .time_to_live(3600)
+        .priority(Priority::Normal)
+        .data(base_data);
+
+    let mut override_data = HashMap::new();
+    override_data.insert("locale", "de");
+    override_data.insert("discount", "20");
+    let overrides = Message::new(vec![])
+        .priority(Priority::High)
+        .notification(NotificationBuilder::new("Sale!").finalize())
+        .data(override_data);
+
+    let merged = base.merge(overrides);
+
+    assert_eq!(merged.registration_ids, Some(vec!["a".to_string(), "b".to_string()]));
+    assert_eq!(merged.collapse_key, Some("promo"));
+    assert_eq!(merged.time_to_live, Some(3600));
+    assert_eq!(merged.priority, Some(Priority::High));
+    assert_eq!(merged.notification, Some(NotificationBuilder::new("Sale!").finalize()));
+
+    let data = merged.data.unwrap();
+    assert_eq!(data.len(), 3);
+    assert_eq!(data["campaign"], "spring");
+    assert_eq!(data["locale"], "de");
+    assert_eq!(data["discount"], "20");
+}
+
+#[test]
+fn should_replace_registration_ids_when_override_has_some() {
+    let merged = Message::new(vec!["a"]).merge(Message::new(vec!["b"]).dry_run(true));
+
+    assert_eq!(merged.registration_ids, Some(vec!["b".to_string()]));
+    assert_eq!(merged.dry_run, Some(true));
+}
+
+#[test]
+fn should_advise_high_priority_for_silent_push() {
+    let message = Message::new(vec!["token"]).content_available(true);
+    assert_eq!(message.validate(), Ok(vec![Advisory::SilentPushNotHighPriority]));
+
+    let message = Message::new(vec!["token"])
+        .content_available(true)
+        .priority(Priority::Normal);
+    assert_eq!(message.validate(), Ok(vec![Advisory::SilentPushNotHighPriority]));
+
+    let message = Message::new(vec!["token"])
+        .content_available(true)
+        .priority(Priority::High);
+    assert_eq!(message.validate(), Ok(vec![]));
+
+    let message = Message::new(vec!["token"])
+        .content_available(true)
+        .notification(NotificationBuilder::new("title").body("body").finalize());
+    assert_eq!(message.validate(), Ok(vec![]));
+}
+
+#[test]
+fn should_reject_messages_gcm_would_refuse() {
+    assert!(Message::new(vec![]).validate().is_err());
+    assert!(Message::new(vec!["token"; 1001]).validate().is_err());
+    assert!(Message::new(vec!["token"; 1000]).validate().is_ok());
+    assert!(Message::new(vec!["token"]).time_to_live(-1).validate().is_err());
+    assert!(Message::new(vec!["token"]).time_to_live(2_419_201).validate().is_err());
+}
+
+#[test]
+fn should_serialize_to_bytes_like_json_string() {
+    let mut data = HashMap::new();
+    data.insert("greeting", "grüß dich");
+    let message = Message::new(vec!["a", "b"])
+        .priority(Priority::High)
+        .notification(NotificationBuilder::new("title").body("body").finalize())
+        .data(data);
+
+    let bytes = message.to_bytes().unwrap();
+
+    assert_eq!(bytes, message.to_json_string().unwrap().into_bytes());
+    assert_eq!(String::from_utf8(bytes).unwrap(), ::gcm_util::to_json(&message).unwrap());
+}
+
+#[test]
+fn should_advise_dry_run_to_topic_or_condition() {
+    let message = Message::new(vec![]).to("/topics/news").dry_run(true);
+    assert_eq!(message.validate(), Ok(vec![Advisory::DryRunWithoutTokens]));
+
+    let message = Message::new(vec![]).condition("'news' in topics").dry_run(true);
+    assert_eq!(message.validate(), Ok(vec![Advisory::DryRunWithoutTokens]));
+
+    let message = Message::new(vec![]).to("/topics/news");
+    assert_eq!(message.validate(), Ok(vec![]));
+
+    let message = Message::new(vec!["token"]).dry_run(true);
+    assert_eq!(message.validate(), Ok(vec![]));
+}
+
+#[test]
+fn should_replace_recipients_when_setting_to() {
+    let message = Message::new(vec!["a"]).to("/topics/news");
+    let json = serde_json::to_value(&message).unwrap();
+
+    assert_eq!(json, json!({"to": "/topics/news"}));
+
+    let merged = Message::new(vec!["a"]).merge(Message::new(vec![]).to("/topics/news"));
+    assert_eq!(merged.to, Some("/topics/news"));
+    assert_eq!(merged.registration_ids, None);
+}
+
+#[test]
+fn should_key_results_by_token() {
+    let response: GcmResponse = serde_json::from_str(MULTICAST_BODY).unwrap();
+    let map = response.as_map(&["a", "b", "c", "d", "b"]);
+
+    assert_eq!(map.len(), 4);
+    assert_eq!(map["a"].message_id, Some("1:0408".to_string()));
+    assert_eq!(map["c"].error, Some("InvalidRegistration".to_string()));
+    assert_eq!(map["d"].message_id, Some("1:1516".to_string()));
+    // "b" was sent twice, its last result wins
+    assert_eq!(map["b"].error, None);
+    assert_eq!(map["b"].registration_id, Some(32));
+
+    assert!(GcmResponse::default().as_map(&["a"]).is_empty());
+}
+
+#[test]
+fn should_build_notification_with_data() {
+    let mut data = HashMap::new();
+    data.insert("order_id", "1234");
+    let notification = NotificationBuilder::new("Shipped").body("On its way").finalize();
+
+    let message = Message::notification_with_data(vec!["a"], notification, data);
+    let json = serde_json::to_value(&message).unwrap();
+
+    assert_eq!(json["registration_ids"], json!(["a"]));
+    assert_eq!(json["notification"]["title"], "Shipped");
+    assert_eq!(json["notification"]["body"], "On its way");
+    assert_eq!(json["data"], json!({"order_id": "1234"}));
+}
+
+#[test]
+fn should_list_all_failed_registration_ids() {
+    let response: GcmResponse = serde_json::from_str(MULTICAST_BODY).unwrap();
+
+    assert_eq!(
+        response.failed_registration_ids(&["a", "b", "c", "d", "e"]),
+        vec!["b".to_string(), "c".to_string()]
+    );
+    assert!(GcmResponse::default().failed_registration_ids(&["a"]).is_empty());
+}
+
+#[test]
+fn should_partition_ids_into_retry_remove_and_canonical() {
+    let response: GcmResponse = serde_json::from_str(MULTICAST_BODY).unwrap();
+    let partitioned = response.partition(&["a", "b", "c", "d", "e"]);
+
+    assert_eq!(partitioned.retry, vec!["b".to_string()]);
+    assert_eq!(partitioned.remove, vec!["c".to_string()]);
+    assert_eq!(partitioned.canonical, vec![("e".to_string(), 32)]);
+
+    let canonical: Vec<&String> = partitioned.canonical.iter().map(|(id, _)| id).collect();
+    for id in &partitioned.retry {
+        assert!(!partitioned.remove.contains(id));
+        assert!(!canonical.contains(&id));
+    }
+    for id in &partitioned.remove {
+        assert!(!canonical.contains(&id));
+    }
+
+    assert_eq!(GcmResponse::default().partition(&["a"]), Partitioned::default());
+}
+
+#[test]
+fn should_tell_topic_from_token_in_to() {
+    let token = "fGx8ZKzJr0E:APA91bHun4MxP5egoKMwt2KZFBaFUH-1RYqx";
+
+    assert!(Message::new(vec![]).to("/topics/news").to_is_topic());
+    assert!(!Message::new(vec![]).to(token).to_is_topic());
+    assert!(!Message::new(vec!["a"]).to_is_topic());
+
+    assert_eq!(Message::new(vec![]).to(token).validate(), Ok(vec![]));
+    assert_eq!(Message::new(vec![]).to("/topics/news").validate(), Ok(vec![]));
+}
+
+#[test]
+fn should_advise_on_topic_names_missing_prefix() {
+    assert_eq!(
+        Message::new(vec![]).to("breaking news").validate(),
+        Ok(vec![Advisory::ToLooksLikeTopicName("breaking news".to_string())])
+    );
+    assert_eq!(
+        Message::new(vec![]).to("topics/news").validate(),
+        Ok(vec![Advisory::ToLooksLikeTopicName("topics/news".to_string())])
+    );
+}
+
+#[test]
+fn should_break_down_data_size_by_key() {
+    let mut data = HashMap::new();
+    data.insert("id", "7");
+    data.insert("body", "a rather long piece of text");
+    data.insert("type", "chat");
+    let message = Message::new(vec!["a"]).data(data);
+
+    let sizes = message.data_sizes();
+
+    assert_eq!(
+        sizes,
+        vec![
+            ("body".to_string(), 31),
+            ("type".to_string(), 8),
+            ("id".to_string(), 3),
+        ]
+    );
+    assert_eq!(sizes.iter().map(|&(_, size)| size).sum::<usize>(), message.data_size());
+    assert_eq!(message.data_size(), 42);
+    assert_eq!(Message::new(vec!["a"]).data_size(), 0);
+}
+
+#[test]
+fn should_build_topic_message_from_topic_string() {
+    let message = Message::try_from("/topics/news-2.0_~%").unwrap();
+
+    assert!(message.to_is_topic());
+    assert_eq!(
+        serde_json::to_value(&message).unwrap(),
+        json!({"to": "/topics/news-2.0_~%"})
+    );
+    assert!(!Message::try_from("token").unwrap().to_is_topic());
+}
+
+#[test]
+fn should_reject_invalid_topic_names() {
+    assert_eq!(
+        Message::try_from("/topics/breaking news").err(),
+        Some(Error::InvalidMessage("invalid topic name `breaking news`".to_string()))
+    );
+    assert!(Message::try_from("/topics/").is_err());
+    assert!(Message::try_from("/topics/a/b").is_err());
+}
+
+#[test]
+fn should_map_message_id_to_collapse_key_on_legacy() {
+    let message = Message::new(vec!["a"]).message_id("order-1234");
+
+    assert_eq!(
+        serde_json::to_value(&message).unwrap(),
+        json!({"registration_ids": ["a"], "collapse_key": "order-1234"})
+    );
+    let explicit = Message::new(vec!["a"]).collapse_key("orders").message_id("order-1234");
+    assert_eq!(serde_json::to_value(&explicit).unwrap()["collapse_key"], "orders");
+}
+
+#[test]
+fn should_use_latest_message_id_as_collapse_key() {
+    let message = Message::new(vec!["a"]).message_id("first").message_id("second");
+
+    assert_eq!(serde_json::to_value(&message).unwrap()["collapse_key"], "second");
+    assert_eq!(message.to_v1()[0]["message"]["android"]["collapse_key"], "second");
+}
+
+#[test]
+fn should_keep_template_collapse_key_when_merging_message_id() {
+    let base = Message::new(vec!["a"]).collapse_key("orders");
+    let merged = base.merge(Message::new(vec![]).message_id("order-1234"));
+
+    assert_eq!(serde_json::to_value(&merged).unwrap()["collapse_key"], "orders");
+    let body = &merged.to_v1()[0]["message"];
+    assert_eq!(body["android"]["collapse_key"], "orders");
+    assert_eq!(body["apns"]["headers"]["apns-collapse-id"], "order-1234");
+}
+
+#[test]
+fn should_map_message_id_to_apns_collapse_id_on_v1() {
+    let bodies = Message::new(vec!["a", "b"])
+        .collapse_key("orders")
+        .message_id("order-1234")
+        .dry_run(true)
+        .to_v1();
+
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(
+        bodies[0],
+        json!({
+            "message": {
+                "token": "a",
+                "android": {"collapse_key": "orders"},
+                "apns": {"headers": {"apns-collapse-id": "order-1234"}}
+            },
+            "validate_only": true
+        })
+    );
+    assert_eq!(bodies[1]["message"]["token"], "b");
+    assert_eq!(
+        Message::new(vec![]).to("/topics/news").message_id("n-1").to_v1()[0]["message"],
+        json!({
+            "topic": "news",
+            "android": {"collapse_key": "n-1"},
+            "apns": {"headers": {"apns-collapse-id": "n-1"}}
+        })
+    );
+}
+
+#[test]
+fn should_reject_malformed_message_ids() {
+    let long = "x".repeat(65);
+
+    assert!(Message::new(vec!["a"]).message_id(&"x".repeat(64)).validate().is_ok());
+    assert!(Message::new(vec!["a"]).message_id(&long).validate().is_err());
+    assert!(Message::new(vec!["a"]).message_id("").validate().is_err());
+    assert!(Message::new(vec!["a"]).message_id("order 1234").validate().is_err());
+    assert!(Message::new(vec!["a"]).message_id("bestellung-ä").validate().is_err());
+}
+
+#[test]
+fn should_parse_lenient_multicast_response() {
+    match GcmResponse::parse_lenient(MULTICAST_BODY).unwrap() {
+        LenientResponse::Multicast(response) => {
+            assert_eq!(response.multicast_id, Some(216));
+            assert_eq!(response.results.unwrap().len(), 5);
+        }
+        other => panic!("expected a multicast response, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_parse_lenient_results_only_response() {
+    let body = r#"{"results": [{"message_id": "1:0408"}, {"error": "NotRegistered"}]}"#;
+
+    match GcmResponse::parse_lenient(body).unwrap() {
+        LenientResponse::Results(results) => {
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[1].error, Some("NotRegistered".to_string()));
+        }
+        other => panic!("expected bare results, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_parse_lenient_single_message_response() {
+    match GcmResponse::parse_lenient(r#"{"message_id": 6084450805553431000}"#).unwrap() {
+        LenientResponse::Single(result) => {
+            assert_eq!(result.message_id, Some("6084450805553431000".to_string()))
+        }
+        other => panic!("expected a single result, got {:?}", other),
+    }
+
+    let failed = GcmResponse::parse_lenient(r#"{"error": "TopicsMessageRateExceeded"}"#)
+        .unwrap()
+        .into_results();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].error, Some("TopicsMessageRateExceeded".to_string()));
+}
+
+#[test]
+fn should_reject_unknown_response_shapes() {
+    assert_eq!(GcmResponse::parse_lenient(r#"{"status": "ok"}"#).err(), Some(Error::InvalidJsonBody));
+    assert_eq!(GcmResponse::parse_lenient("[]").err(), Some(Error::InvalidJsonBody));
+}
+
+#[test]
+fn should_keep_android_and_apns_priority_apart_on_v1() {
+    let message = Message::new(vec!["a"]).priority(Priority::High).apns_priority(5);
+
+    assert_eq!(
+        message.to_v1()[0]["message"],
+        json!({
+            "token": "a",
+            "android": {"priority": "HIGH"},
+            "apns": {"headers": {"apns-priority": "5"}}
+        })
+    );
+    assert_eq!(serde_json::to_value(&message).unwrap()["priority"], "high");
+    assert!(!message.to_json_string().unwrap().contains("apns"));
+}
+
+#[test]
+fn should_reject_unknown_apns_priority() {
+    assert!(Message::new(vec!["a"]).apns_priority(10).validate().is_ok());
+    assert_eq!(
+        Message::new(vec!["a"]).apns_priority(7).validate(),
+        Err(Error::InvalidMessage("apns_priority must be 5 or 10, got 7".to_string()))
+    );
+}
+
+#[test]
+fn should_diff_against_template_and_merge_back() {
+    use serde::Deserialize;
+
+    let mut template_data = HashMap::new();
+    template_data.insert("type", "score");
+    template_data.insert("league", "ipl");
+    let template = Message::new(vec!["a", "b"])
+        .collapse_key("scores")
+        .time_to_live(60)
+        .data(template_data);
+
+    let mut data = HashMap::new();
+    data.insert("type", "score");
+    data.insert("league", "ipl");
+    data.insert("match", "42");
+    let message = template
+        .clone()
+        .registration_ids(vec!["c"])
+        .priority(Priority::High)
+        .data(data);
+
+    let delta = message.diff(&template);
+    assert_eq!(
+        delta,
+        json!({"registration_ids": ["c"], "priority": "high", "data": {"match": "42"}})
+    );
+
+    let restored = template.clone().merge(Message::deserialize(&delta).unwrap());
+    assert_eq!(
+        serde_json::to_value(&restored).unwrap(),
+        serde_json::to_value(&message).unwrap()
+    );
+    assert_eq!(template.diff(&template), json!({}));
+}
+
+#[test]
+fn should_classify_silent_and_notification_messages() {
+    let mut data = HashMap::new();
+    data.insert("sync", "inbox");
+    let notification = NotificationBuilder::new("New mail").finalize();
+
+    let silent = Message::new(vec!["a"]).data(data.clone());
+    assert!(silent.is_silent());
+    assert!(!silent.is_notification());
+    assert!(Message::new(vec!["a"]).content_available(true).is_silent());
+
+    let shown = Message::new(vec!["a"]).notification(notification.clone());
+    assert!(shown.is_notification());
+    assert!(!shown.is_silent());
+
+    let combined = Message::notification_with_data(vec!["a"], notification, data);
+    assert!(combined.is_notification());
+    assert!(!combined.is_silent());
+
+    let empty = Message::new(vec!["a"]);
+    assert!(!empty.is_silent());
+    assert!(!empty.is_notification());
+}
+
+#[test]
+fn should_lint_every_rejection_with_a_suggestion() {
+    let lints = Message::new(vec!["token"; 1001]).time_to_live(-1).apns_priority(1).lint();
+
+    assert_eq!(lints.len(), 3);
+    assert!(lints.iter().all(|lint| lint.severity == Severity::Error));
+    assert_eq!(lints[0].message, "Message has 1001 registration ids, at most 1000 are allowed");
+    assert_eq!(lints[0].suggestion, "split the ids into batches of at most 1000");
+}
+
+#[test]
+fn should_lint_data_only_push_at_normal_priority() {
+    let message = Message::new(vec!["a"]).content_available(true).delay_while_idle(true);
+
+    let lints = message.lint();
+
+    assert_eq!(lints.len(), 2);
+    assert_eq!(lints[0].severity, Severity::Warning);
+    assert_eq!(lints[0].message, Advisory::SilentPushNotHighPriority.to_string());
+    assert_eq!(lints[0].suggestion, "send it with Priority::High");
+    assert_eq!(lints[1].message, "delay_while_idle is deprecated and ignored by FCM");
+    assert!(Message::new(vec!["a"]).priority(Priority::High).content_available(true).lint().is_empty());
+}
+
+#[test]
+fn should_lint_oversized_data() {
+    let value = "x".repeat(4096);
+    let mut data = HashMap::new();
+    data.insert("blob", value.as_str());
+
+    let message = Message::new(vec!["a"]).data(data);
+    let lints = message.lint();
+
+    assert_eq!(lints.len(), 1);
+    assert_eq!(lints[0].severity, Severity::Error);
+    assert_eq!(lints[0].message, "data is 4100 bytes, GCM accepts at most 4096");
+    assert_eq!(
+        message.validate(),
+        Err(Error::InvalidMessage("data is 4100 bytes, GCM accepts at most 4096".to_string()))
+    );
+}
+
+#[test]
+fn should_advise_on_restricted_package_for_ios_only_messages() {
+    let badge = NotificationBuilder::new("New mail").body("3 unread").badge("3").finalize();
+    let ios_only = Message::new(vec!["a"])
+        .restricted_package_name("com.example.mail")
+        .apns_priority(10)
+        .notification(badge.clone());
+
+    assert_eq!(ios_only.validate(), Ok(vec![Advisory::RestrictedPackageOnIosOnlyMessage]));
+
+    let cross_platform = NotificationBuilder::new("New mail").body("3 unread").badge("3").color("#ff0000").finalize();
+    let message = Message::new(vec!["a"])
+        .restricted_package_name("com.example.mail")
+        .notification(cross_platform);
+    assert_eq!(message.validate(), Ok(vec![]));
+    assert_eq!(Message::new(vec!["a"]).notification(badge).apns_priority(10).validate(), Ok(vec![]));
+
+    let mut data = HashMap::new();
+    data.insert("sync", "inbox");
+    let silent_push = Message::new(vec!["a"])
+        .restricted_package_name("com.example.mail")
+        .content_available(true)
+        .priority(Priority::High)
+        .data(data);
+    assert_eq!(silent_push.validate(), Ok(vec![]));
+}
+
+#[test]
+fn should_advise_on_incomplete_notifications() {
+    let title_only = NotificationBuilder::new("New mail").finalize();
+    assert_eq!(
+        Message::new(vec!["a"]).notification(title_only).validate(),
+        Ok(vec![Advisory::NotificationTitleWithoutBody])
+    );
+
+    let body_only = NotificationBuilder::new("").body("3 unread").finalize();
+    assert_eq!(
+        Message::new(vec!["a"]).notification(body_only).validate(),
+        Ok(vec![Advisory::NotificationBodyWithoutTitle])
+    );
+
+    let localized = NotificationBuilder::new("New mail").body_loc_key("unread_count").finalize();
+    assert_eq!(Message::new(vec!["a"]).notification(localized).validate(), Ok(vec![]));
+}
+
+#[test]
+fn should_fit_fewer_tokens_with_a_heavy_payload() {
+    let text = "x".repeat(3000);
+    let mut data = HashMap::new();
+    data.insert("text", text.as_str());
+    let heavy = Message::new(vec![]).data(data);
+    let token = "t".repeat(152);
+
+    let size = gcm_util::optimal_batch_size(&heavy, token.len(), 64 * 1024);
+
+    assert!(size > 0 && size < 1000);
+    let fits = heavy.clone().registration_ids(vec![token.as_str(); size]);
+    assert!(fits.to_json_string().unwrap().len() <= 64 * 1024);
+    let one_more = heavy.registration_ids(vec![token.as_str(); size + 1]);
+    assert!(one_more.to_json_string().unwrap().len() > 64 * 1024);
+}
+
+#[test]
+fn should_cap_batch_size_at_gcm_limit() {
+    let light = Message::new(vec![]).collapse_key("ping");
+
+    assert_eq!(gcm_util::optimal_batch_size(&light, 152, 1024 * 1024), 1000);
+    assert_eq!(gcm_util::optimal_batch_size(&light, 152, 100), 0);
+}
+
+#[test]
+fn should_write_v1_ttl_as_seconds_string() {
+    let message = Message::new(vec!["a"]).time_to_live(3600);
+    assert_eq!(message.to_v1()[0]["message"]["android"], json!({"ttl": "3600s"}));
+    assert_eq!(serde_json::to_value(&message).unwrap()["time_to_live"], 3600);
+
+    let now_or_never = Message::new(vec!["a"]).time_to_live(0);
+    assert_eq!(now_or_never.to_v1()[0]["message"]["android"]["ttl"], "0s");
+}
+
+#[test]
+fn should_count_results_when_response_has_no_counts() {
+    let body = r#"{
+        "multicast_id": 216,
+        "results": [
+            {"message_id": "1:0408"},
+            {"error": "Unavailable"},
+            {"message_id": "1:2342", "registration_id": 32}
+        ]
+    }"#;
+
+    let mut response: GcmResponse = serde_json::from_str(body).unwrap();
+
+    assert_eq!(response.success, None);
+    assert_eq!(response.success_count(), 2);
+    assert_eq!(response.failure_count(), 1);
+
+    response.build_reg_ids_by_error_map(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+    let ids_by_error = response.ids_by_error.unwrap();
+    assert_eq!(ids_by_error.len(), 1);
+    assert_eq!(ids_by_error["Unavailable"], vec!["b".to_string()]);
+
+    let counted: GcmResponse = serde_json::from_str(MULTICAST_BODY).unwrap();
+    assert_eq!((counted.success_count(), counted.failure_count()), (3, 2));
+    assert_eq!(GcmResponse::default().success_count(), 0);
+}
+
+#[test]
+fn should_surface_error_description() {
+    let body = r#"{
+        "multicast_id": 216,
+        "success": 0,
+        "failure": 1,
+        "results": [
+            {"error": "InvalidParameters", "error_description": "time_to_live must be at most 2419200"}
+        ]
+    }"#;
+
+    let response: GcmResponse = serde_json::from_str(body).unwrap();
+    let result = &response.results.unwrap()[0];
+    assert_eq!(result.error, Some("InvalidParameters".to_string()));
+    assert_eq!(
+        result.error_description,
+        Some("time_to_live must be at most 2419200".to_string())
+    );
+
+    let mut streamed = Vec::new();
+    GcmResponse::parse_streaming(body, |result| streamed.push(result)).unwrap();
+    assert_eq!(streamed[0].error_description, result.error_description);
+
+    let rejected = r#"{"error": "InvalidRegistration", "error_description": "token is malformed"}"#;
+    assert_eq!(
+        gcm_util::status_error(400, rejected),
+        Error::InvalidRequest("InvalidRegistration: token is malformed".to_string())
+    );
+    assert_eq!(
+        gcm_util::status_error(400, "Missing registration_ids"),
+        Error::InvalidMessage("Missing registration_ids".to_string())
+    );
+    assert_eq!(
+        serde_json::from_str::<GcmResponse>(MULTICAST_BODY).unwrap().results.unwrap()[0].error_description,
+        None
+    );
+}
+
+#[test]
+fn should_read_and_validate_message_from_value() {
+    let input = json!({
+        "registration_ids": ["a", "b"],
+        "priority": "high",
+        "time_to_live": 60,
+        "data": {"order_id": "1234"}
+    });
+    let message = Message::from_value(&input).unwrap();
+    assert_eq!(message.registration_ids, Some(vec!["a".to_string(), "b".to_string()]));
+    assert_eq!(message.priority, Some(Priority::High));
+    assert!(message.extra_fields().is_empty());
+    assert_eq!(serde_json::to_value(&message).unwrap(), input);
+}
+
+#[test]
+fn should_read_notification_without_icon_from_value() {
+    let input = json!({"to": "a", "notification": {"title": "Shipped", "body": "On its way"}});
+    let message = Message::from_value(&input).unwrap();
+
+    assert_eq!(serde_json::to_value(&message).unwrap(), input);
+}
+
+#[test]
+fn should_reject_invalid_fields_from_value() {
+    let wrong_type = json!({"to": "a", "time_to_live": "1h"});
+    match Message::from_value(&wrong_type) {
+        Err(Error::InvalidMessage(text)) => assert!(text.contains("\"1h\""), "{}", text),
+        other => panic!("unexpected {:?}", other.map(|m| m.to_json_string())),
+    }
+
+    let wrong_value = json!({"to": "a", "priority": "urgent"});
+    assert!(Message::from_value(&wrong_value).is_err());
+
+    // well formed, but GCM would refuse it
+    let too_long = json!({"to": "a", "time_to_live": 5_000_000});
+    assert!(Message::from_value(&too_long).is_err());
+}
+
+#[test]
+fn should_keep_unknown_fields_from_value() {
+    let input = json!({
+        "to": "a",
+        "fcm_options": {"analytics_label": "spring"},
+        "mutable_content": true
+    });
+    let message = Message::from_value(&input).unwrap();
+
+    assert_eq!(message.extra_fields().len(), 2);
+    assert_eq!(message.extra_fields()["mutable_content"], true);
+    assert_eq!(serde_json::to_value(&message).unwrap(), input);
+
+    let merged = Message::new(vec!["b"]).merge(message);
+    assert_eq!(merged.extra_fields()["fcm_options"]["analytics_label"], "spring");
+}
+
+#[test]
+fn should_clone_payload_with_new_ids() {
+    let mut data = HashMap::new();
+    data.insert("campaign", "spring");
+    let template = Message::new(vec![])
+        .to("/topics/sale")
+        .collapse_key("spring")
+        .priority(Priority::High)
+        .data(data)
+        .notification(NotificationBuilder::new("Spring sale").body("20% off").finalize());
+
+    let segment = template.clone_with_ids(vec!["b".to_string(), "c".to_string()]);
+
+    assert_eq!(segment.registration_ids, Some(vec!["b".to_string(), "c".to_string()]));
+    assert_eq!(segment.to, None);
+    assert_eq!(segment.collapse_key, Some("spring"));
+    assert_eq!(segment.priority, Some(Priority::High));
+    assert_eq!(segment.data, template.data);
+    assert_eq!(segment.notification, template.notification);
+    assert_eq!(template.to, Some("/topics/sale"));
+}
@@ -0,0 +1,339 @@
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use hyper::header;
+use hyper::header::Headers;
+use hyper::mime::{Attr, Mime, SubLevel, TopLevel, Value};
+use hyper::client::pool::Pool;
+use hyper::client::Response;
+use hyper::net::{HttpsConnector, NetworkConnector};
+use hyper::Client;
+use hyper_native_tls::NativeTlsClient;
+use serde_json::Value as JsonValue;
+
+use gcm_util;
+use message::response::GcmError;
+
+/// The raw answer to a posted message body, before it is interpreted as a
+/// `GcmResponse` or a `GcmError`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransportResponse {
+    pub status: u16,
+    /// Seconds to wait before retrying, from the `Retry-After` header.
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    pub body: String,
+}
+
+/// Moves a serialized message body to GCM and brings back whatever came back.
+/// `GcmSender` talks to GCM over HTTP through `HttpTransport`, other
+/// implementations can stand in for it (see `RecordingTransport` and `ReplayTransport`).
+pub trait SendTransport: Send + Sync {
+    fn post(&self, body: &str) -> Result<TransportResponse, GcmError>;
+
+    /// Get ready to post without delay, e.g. by connecting ahead of time.
+    /// Transports with nothing to prepare keep the default, which does nothing.
+    fn warm_up(&self) -> Result<(), GcmError> {
+        Ok(())
+    }
+
+    /// Post to `google_api` whenever connecting to the usual endpoint fails.
+    /// Transports without an endpoint of their own keep the default, which
+    /// ignores it.
+    fn set_fallback_endpoint(&mut self, _google_api: String) {}
+}
+
+/// The default transport, posting to a GCM/FCM endpoint with a server key.
+/// Every post opens a connection of its own until `warm_up` is called, from
+/// then on connections are kept alive and reused across posts. A response
+/// that breaks off while its body is read comes back as a 500 with the part
+/// that was read.
+pub struct HttpTransport {
+    google_api: String,
+    fallback_api: Option<String>,
+    api_key: String,
+    client: Client,
+    pooled: Client,
+    keep_alive: AtomicBool,
+}
+
+impl HttpTransport {
+    pub fn new(google_api: String, api_key: String) -> HttpTransport {
+        let ssl = NativeTlsClient::new().unwrap();
+        let client = Client::with_connector(TaggedConnector(HttpsConnector::new(ssl.clone())));
+        let connector = TaggedConnector(HttpsConnector::new(ssl));
+        let pooled = Client::with_connector(Pool::with_connector(Default::default(), connector));
+
+        HttpTransport {
+            google_api,
+            fallback_api: None,
+            api_key,
+            client,
+            pooled,
+            keep_alive: AtomicBool::new(false),
+        }
+    }
+
+    fn client(&self) -> &Client {
+        if self.keep_alive.load(Ordering::SeqCst) {
+            &self.pooled
+        } else {
+            &self.client
+        }
+    }
+
+    // hyper's `Headers` is not `Sync`, so they are put together per request
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        let mime = Mime(
+            TopLevel::Application,
+            SubLevel::Json,
+            vec![(Attr::Charset, Value::Utf8)],
+        );
+
+        headers.set(header::Authorization("key=".to_string() + &self.api_key));
+        headers.set(header::ContentType(mime));
+        headers
+    }
+
+    fn send_to(&self, google_api: &str, body: &str) -> Result<Response, hyper::Error> {
+        self.client()
+            .post(google_api)
+            .body(body.as_bytes())
+            .headers(self.headers())
+            .send()
+    }
+}
+
+/// Wraps the connector of `HttpTransport` to tell its errors apart: whatever
+/// fails while connecting, TLS handshake included, fails before the request
+/// is written.
+struct TaggedConnector<C>(C);
+
+/// An error of `TaggedConnector`, carried as the source of a `hyper::Error::Io`.
+#[derive(Debug)]
+struct ConnectFailed(hyper::Error);
+
+impl Display for ConnectFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connecting failed: {}", self.0)
+    }
+}
+
+impl error::Error for ConnectFailed {
+    fn description(&self) -> &str {
+        "connecting failed"
+    }
+}
+
+impl<C: NetworkConnector> NetworkConnector for TaggedConnector<C> {
+    type Stream = C::Stream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<C::Stream> {
+        self.0.connect(host, port, scheme).map_err(|error| {
+            hyper::Error::Io(io::Error::new(io::ErrorKind::Other, ConnectFailed(error)))
+        })
+    }
+}
+
+/// Whether `error` came from connecting, before the request could reach the
+/// endpoint, so that posting it elsewhere cannot deliver the message twice.
+fn is_connect_error(error: &hyper::Error) -> bool {
+    match *error {
+        hyper::Error::Io(ref error) => error
+            .get_ref()
+            .is_some_and(|source| source.is::<ConnectFailed>()),
+        _ => false,
+    }
+}
+
+impl SendTransport for HttpTransport {
+    fn post(&self, body: &str) -> Result<TransportResponse, GcmError> {
+        let mut response = match (self.send_to(&self.google_api, body), &self.fallback_api) {
+            (Err(ref error), &Some(ref fallback_api)) if is_connect_error(error) => {
+                self.send_to(fallback_api, body)
+            }
+            (response, _) => response,
+        }
+        .map_err(|_| GcmError::ServerError)?;
+
+        // only the delay-seconds form of Retry-After is understood
+        let retry_after = response
+            .headers
+            .get_raw("Retry-After")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(|value| value.trim().parse().ok());
+
+        let mut body = Vec::new();
+        match response.read_to_end(&mut body) {
+            Ok(_) => Ok(TransportResponse {
+                status: response.status.to_u16(),
+                retry_after,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            }),
+            // keep what arrived, it may confirm some deliveries (see `GcmSender::send_idempotent`)
+            Err(_) => Ok(TransportResponse {
+                status: 500,
+                retry_after,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            }),
+        }
+    }
+
+    /// Open a connection (TLS handshake included) with a `HEAD` request, which
+    /// leaves it in the pool for the next post, and keep connections alive from
+    /// now on. Nothing closes idle pooled connections: one the endpoint closed
+    /// meanwhile fails the post it is reused for.
+    fn warm_up(&self) -> Result<(), GcmError> {
+        self.keep_alive.store(true, Ordering::SeqCst);
+        let mut response = self
+            .pooled
+            .head(&self.google_api)
+            .send()
+            .map_err(|_| GcmError::ServerError)?;
+
+        // drain whatever came back so the connection can be reused
+        let _ = io::copy(&mut response, &mut io::sink());
+        Ok(())
+    }
+
+    fn set_fallback_endpoint(&mut self, google_api: String) {
+        self.fallback_api = Some(google_api);
+    }
+}
+
+/// Key under which a request is recorded and looked up on replay: the target
+/// (registration ids, `to` or `condition`) followed by a hash of the remaining payload.
+pub fn request_key(body: &str) -> String {
+    let mut value: JsonValue = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return format!("#{:016x}", gcm_util::fnv1a(body.as_bytes())),
+    };
+
+    let mut target = Vec::new();
+    if let Some(fields) = value.as_object_mut() {
+        for name in &["registration_ids", "to", "condition"] {
+            if let Some(field) = fields.remove(*name) {
+                match field {
+                    JsonValue::String(s) => target.push(s),
+                    JsonValue::Array(ids) => target.extend(ids.iter().map(|id| match *id {
+                        JsonValue::String(ref s) => s.clone(),
+                        ref other => other.to_string(),
+                    })),
+                    other => target.push(other.to_string()),
+                }
+            }
+        }
+    }
+
+    format!(
+        "{}#{:016x}",
+        target.join(","),
+        gcm_util::fnv1a(value.to_string().as_bytes())
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+struct Recording {
+    key: String,
+    request: String,
+    response: TransportResponse,
+}
+
+/// Wraps another transport and appends every request/response pair to a file,
+/// one JSON object per line, for later use with `ReplayTransport`.
+pub struct RecordingTransport<T: SendTransport> {
+    inner: T,
+    file: Mutex<File>,
+}
+
+impl<T: SendTransport> RecordingTransport<T> {
+    pub fn new<P: AsRef<Path>>(inner: T, path: P) -> io::Result<RecordingTransport<T>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordingTransport {
+            inner,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl<T: SendTransport> SendTransport for RecordingTransport<T> {
+    fn post(&self, body: &str) -> Result<TransportResponse, GcmError> {
+        let response = self.inner.post(body)?;
+        let recording = Recording {
+            key: request_key(body),
+            request: body.to_string(),
+            response: response.clone(),
+        };
+
+        // a broken recording must not fail the actual send
+        if let Ok(line) = serde_json::to_string(&recording) {
+            if let Ok(mut file) = self.file.lock() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+
+        Ok(response)
+    }
+
+    fn warm_up(&self) -> Result<(), GcmError> {
+        self.inner.warm_up()
+    }
+
+    fn set_fallback_endpoint(&mut self, google_api: String) {
+        self.inner.set_fallback_endpoint(google_api)
+    }
+}
+
+/// Serves responses captured by `RecordingTransport`, matching requests by
+/// `request_key`. Requests recorded several times are answered in recorded
+/// order, the last answer being repeated once the others are used up.
+pub struct ReplayTransport {
+    responses: Mutex<HashMap<String, VecDeque<TransportResponse>>>,
+}
+
+impl ReplayTransport {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ReplayTransport> {
+        let mut responses: HashMap<String, VecDeque<TransportResponse>> = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let recording: Recording = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            responses
+                .entry(recording.key)
+                .or_default()
+                .push_back(recording.response);
+        }
+
+        Ok(ReplayTransport {
+            responses: Mutex::new(responses),
+        })
+    }
+}
+
+impl SendTransport for ReplayTransport {
+    fn post(&self, body: &str) -> Result<TransportResponse, GcmError> {
+        let key = request_key(body);
+        let mut responses = self.responses.lock().map_err(|_| GcmError::ServerError)?;
+
+        match responses.get_mut(&key) {
+            Some(ref mut queue) if queue.len() > 1 => Ok(queue.pop_front().unwrap()),
+            Some(ref queue) if !queue.is_empty() => Ok(queue[0].clone()),
+            _ => Err(GcmError::InvalidMessage(format!(
+                "No recorded response for request {}",
+                key
+            ))),
+        }
+    }
+}
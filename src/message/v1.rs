@@ -0,0 +1,145 @@
+use serde_json::{self, Map, Value as JsonValue};
+
+use message::{Message, Priority};
+
+// android.notification keys that carry over unchanged from the legacy notification
+const ANDROID_NOTIFICATION_KEYS: &[&str] = &[
+    "icon",
+    "sound",
+    "tag",
+    "color",
+    "click_action",
+    "body_loc_key",
+    "body_loc_args",
+    "title_loc_key",
+    "title_loc_args",
+];
+
+impl<'a> Message<'a> {
+    /// Convert the message to request bodies for the FCM HTTP v1 API
+    /// (`projects/<project>/messages:send`). v1 addresses a single target per
+    /// request, so there is one body per registration id, or a single one for
+    /// a topic or condition.
+    ///
+    /// Android specific settings, `priority` among them, go into the `android`
+    /// block, `time_to_live` as a `ttl` string such as `"3600s"`.
+    /// `apns_priority` becomes the `apns-priority` header, `message_id` the
+    /// `apns-collapse-id` header, and the Android collapse key when none is
+    /// set.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::Message;
+    ///
+    /// let bodies = Message::new(vec!["a", "b"]).collapse_key("scores").to_v1();
+    ///
+    /// assert_eq!(bodies.len(), 2);
+    /// assert_eq!(bodies[1]["message"]["token"], "b");
+    /// assert_eq!(bodies[1]["message"]["android"]["collapse_key"], "scores");
+    /// ```
+    pub fn to_v1(&self) -> Vec<JsonValue> {
+        let targets: Vec<(&str, String)> = if let Some(to) = self.to {
+            match to.strip_prefix("/topics/") {
+                Some(topic) => vec![("topic", topic.to_string())],
+                None => vec![("token", to.to_string())],
+            }
+        } else if let Some(condition) = self.condition {
+            vec![("condition", condition.to_string())]
+        } else {
+            self.registration_ids
+                .iter()
+                .flatten()
+                .map(|id| ("token", id.clone()))
+                .collect()
+        };
+
+        let message = self.v1_message();
+        targets
+            .into_iter()
+            .map(|(kind, target)| {
+                let mut message = message.clone();
+                message.insert(kind.to_string(), JsonValue::String(target));
+
+                let mut body = Map::new();
+                body.insert("message".to_string(), JsonValue::Object(message));
+                if self.dry_run == Some(true) {
+                    body.insert("validate_only".to_string(), JsonValue::Bool(true));
+                }
+                JsonValue::Object(body)
+            })
+            .collect()
+    }
+
+    // everything but the target
+    fn v1_message(&self) -> Map<String, JsonValue> {
+        let mut message = Map::new();
+        let mut android = Map::new();
+        let mut apns_headers = Map::new();
+
+        if let Some(ref data) = self.data {
+            let data = data
+                .iter()
+                .map(|(key, value)| (key.clone(), JsonValue::String(value.clone())))
+                .collect();
+            message.insert("data".to_string(), JsonValue::Object(data));
+        }
+
+        if let Some(ref notification) = self.notification {
+            if let Ok(JsonValue::Object(mut legacy)) = serde_json::to_value(notification) {
+                let mut common = Map::new();
+                for key in &["title", "body"] {
+                    if let Some(value) = legacy.remove(*key) {
+                        common.insert(key.to_string(), value);
+                    }
+                }
+                message.insert("notification".to_string(), JsonValue::Object(common));
+
+                let specific: Map<String, JsonValue> = legacy
+                    .into_iter()
+                    .filter(|(key, _)| ANDROID_NOTIFICATION_KEYS.contains(&key.as_str()))
+                    .collect();
+                if !specific.is_empty() {
+                    android.insert("notification".to_string(), JsonValue::Object(specific));
+                }
+            }
+        }
+
+        if let Some(collapse_key) = self.collapse_key.or(self.message_id) {
+            android.insert("collapse_key".to_string(), collapse_key.into());
+        }
+        if let Some(priority) = self.priority {
+            let priority = match priority {
+                Priority::Normal => "NORMAL",
+                Priority::High => "HIGH",
+            };
+            android.insert("priority".to_string(), priority.into());
+        }
+        if let Some(ttl) = self.time_to_live {
+            // a protobuf Duration in JSON: seconds with an `s` suffix
+            android.insert("ttl".to_string(), format!("{}s", ttl).into());
+        }
+        if let Some(package) = self.restricted_package_name {
+            android.insert("restricted_package_name".to_string(), package.into());
+        }
+        if let Some(message_id) = self.message_id {
+            apns_headers.insert("apns-collapse-id".to_string(), message_id.into());
+        }
+        if let Some(apns_priority) = self.apns_priority {
+            apns_headers.insert("apns-priority".to_string(), apns_priority.to_string().into());
+        }
+
+        if !android.is_empty() {
+            message.insert("android".to_string(), JsonValue::Object(android));
+        }
+        if !apns_headers.is_empty() {
+            message.insert("apns".to_string(), json_object("headers", apns_headers));
+        }
+
+        message
+    }
+}
+
+fn json_object(key: &str, value: Map<String, JsonValue>) -> JsonValue {
+    let mut object = Map::new();
+    object.insert(key.to_string(), JsonValue::Object(value));
+    JsonValue::Object(object)
+}
@@ -0,0 +1,299 @@
+use std::fmt::{self, Display};
+
+use message::response::GcmError;
+use message::{Message, Priority};
+
+/// Most registration ids GCM accepts in a single multicast request.
+pub const MAX_REGISTRATION_IDS: usize = 1000;
+
+/// Longest `time_to_live` GCM accepts, 4 weeks in seconds.
+pub const MAX_TIME_TO_LIVE: i32 = 2_419_200;
+
+/// Largest `data` payload GCM accepts, in bytes (see `Message::data_size`).
+pub const MAX_DATA_SIZE: usize = 4096;
+
+/// Longest client supplied `message_id`, bound by `apns-collapse-id`.
+pub const MAX_MESSAGE_ID_LEN: usize = 64;
+
+/// Something about a message that GCM accepts but that likely does not behave
+/// the way it was meant to. Reported by `Message::validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Advisory {
+    /// A `content_available` push without a notification, at normal priority.
+    /// Android devices in Doze may not wake up for it before the next
+    /// maintenance window; silent data pushes should go out with `Priority::High`.
+    SilentPushNotHighPriority,
+    /// A dry run to a topic or condition. GCM answers it with a `message_id`
+    /// but, having no individual registration ids to check, tells next to
+    /// nothing about whether the real send would reach anyone.
+    DryRunWithoutTokens,
+    /// `to` holds neither a topic nor anything like a registration token,
+    /// e.g. a topic name missing its `/topics/` prefix. GCM would treat it as a
+    /// token and reject it as `InvalidRegistration`.
+    ToLooksLikeTopicName(String),
+    /// `restricted_package_name` on a message carrying only iOS settings
+    /// (`apns_priority`, `content_available`, a notification badge) and
+    /// nothing for Android, not even `data`. The field only means something
+    /// to Android, the message likely comes from a cross-platform template
+    /// set up wrong.
+    RestrictedPackageOnIosOnlyMessage,
+    /// A notification with a title but neither a body nor a `body_loc_key`.
+    /// Some clients render it as a bare heading, or with a blank line below.
+    NotificationTitleWithoutBody,
+    /// A notification with a body but an empty title and no `title_loc_key`.
+    NotificationBodyWithoutTitle,
+}
+
+impl Advisory {
+    fn suggestion(&self) -> &'static str {
+        match *self {
+            Advisory::SilentPushNotHighPriority => "send it with Priority::High",
+            Advisory::DryRunWithoutTokens => {
+                "dry run to a sample of registration ids instead, see GcmSender::dry_run_sample"
+            }
+            Advisory::ToLooksLikeTopicName(_) => "prefix topic names with /topics/",
+            Advisory::RestrictedPackageOnIosOnlyMessage => {
+                "drop restricted_package_name from iOS sends, or check the template"
+            }
+            Advisory::NotificationTitleWithoutBody => "give the notification a body",
+            Advisory::NotificationBodyWithoutTitle => "give the notification a title",
+        }
+    }
+}
+
+impl Display for Advisory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Advisory::SilentPushNotHighPriority => write!(
+                f,
+                "content_available without a notification at normal priority may not wake Android devices in Doze"
+            ),
+            Advisory::DryRunWithoutTokens => write!(
+                f,
+                "dry_run to a topic or condition does not validate any registration ids"
+            ),
+            Advisory::ToLooksLikeTopicName(ref to) => write!(
+                f,
+                "`to` value {:?} is not a registration token, topics need the /topics/ prefix",
+                to
+            ),
+            Advisory::RestrictedPackageOnIosOnlyMessage => write!(
+                f,
+                "restricted_package_name only applies to Android, but the message only carries iOS settings"
+            ),
+            Advisory::NotificationTitleWithoutBody => {
+                write!(f, "the notification has a title but no body")
+            }
+            Advisory::NotificationBodyWithoutTitle => {
+                write!(f, "the notification has a body but no title")
+            }
+        }
+    }
+}
+
+/// How bad a `Lint` is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// GCM would reject the message.
+    Error,
+    /// GCM accepts the message, but it likely does not behave as meant.
+    Warning,
+}
+
+/// A problem found by `Message::lint`, with what to do about it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub severity: Severity,
+    pub message: String,
+    pub suggestion: String,
+}
+
+impl Lint {
+    fn new(severity: Severity, message: String, suggestion: &str) -> Lint {
+        Lint {
+            severity,
+            message,
+            suggestion: suggestion.to_string(),
+        }
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Check the message before sending it. Messages GCM would reject fail with
+    /// `GcmError::InvalidMessage`, otherwise the advisories found are returned;
+    /// they never prevent sending.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Advisory, Message};
+    ///
+    /// let message = Message::new(vec!["<registration id>"]).content_available(true);
+    ///
+    /// assert_eq!(message.validate(), Ok(vec![Advisory::SilentPushNotHighPriority]));
+    /// ```
+    pub fn validate(&self) -> Result<Vec<Advisory>, GcmError> {
+        match self.rejections().into_iter().next() {
+            Some((message, _)) => Err(GcmError::InvalidMessage(message)),
+            None => Ok(self.advisories()),
+        }
+    }
+
+    /// Every problem `validate` knows of, all rejections rather than the first
+    /// one, plus checks too opinionated for `validate`, each with a
+    /// suggestion. Meant for admin UIs and command line tools showing the
+    /// findings to people.
+    /// # Examples:
+    /// ```rust
+    /// use gcm::{Message, Severity};
+    ///
+    /// let lints = Message::new(vec![]).time_to_live(-1).lint();
+    ///
+    /// assert_eq!(lints.len(), 2);
+    /// assert!(lints.iter().all(|lint| lint.severity == Severity::Error));
+    /// ```
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut lints: Vec<Lint> = self
+            .rejections()
+            .into_iter()
+            .map(|(message, suggestion)| Lint::new(Severity::Error, message, suggestion))
+            .collect();
+
+        lints.extend(self.advisories().into_iter().map(|advisory| {
+            Lint::new(Severity::Warning, advisory.to_string(), advisory.suggestion())
+        }));
+
+        if self.delay_while_idle.is_some() {
+            lints.push(Lint::new(
+                Severity::Warning,
+                "delay_while_idle is deprecated and ignored by FCM".to_string(),
+                "remove it, use time_to_live to bound how late a message may arrive",
+            ));
+        }
+        if self.is_notification() && self.content_available == Some(true) {
+            lints.push(Lint::new(
+                Severity::Warning,
+                "content_available on a notification message makes iOS wake the app as well as show it".to_string(),
+                "drop content_available unless the app has work to do in the background",
+            ));
+        }
+
+        lints
+    }
+
+    // what GCM would reject, with a suggestion each
+    fn rejections(&self) -> Vec<(String, &'static str)> {
+        let mut rejections = Vec::new();
+
+        let ids = self.registration_ids.as_ref().map_or(0, Vec::len);
+        if ids == 0 && self.to.is_none() && self.condition.is_none() {
+            rejections.push((
+                "Message has neither registration ids nor a `to` or `condition`".to_string(),
+                "add registration ids, or address a topic or condition",
+            ));
+        }
+        if ids > MAX_REGISTRATION_IDS {
+            rejections.push((
+                format!(
+                    "Message has {} registration ids, at most {} are allowed",
+                    ids, MAX_REGISTRATION_IDS
+                ),
+                "split the ids into batches of at most 1000",
+            ));
+        }
+        let data_size = self.data_size();
+        if data_size > MAX_DATA_SIZE {
+            rejections.push((
+                format!(
+                    "data is {} bytes, GCM accepts at most {}",
+                    data_size, MAX_DATA_SIZE
+                ),
+                "send a reference and let the app fetch the content",
+            ));
+        }
+        if let Some(ttl) = self.time_to_live {
+            if !(0..=MAX_TIME_TO_LIVE).contains(&ttl) {
+                rejections.push((
+                    format!(
+                        "time_to_live must be between 0 and {} seconds, got {}",
+                        MAX_TIME_TO_LIVE, ttl
+                    ),
+                    "pick a time_to_live of at most 4 weeks",
+                ));
+            }
+        }
+        if let Some(id) = self.message_id {
+            if id.is_empty()
+                || id.len() > MAX_MESSAGE_ID_LEN
+                || !id.chars().all(|c| c.is_ascii_graphic())
+            {
+                rejections.push((
+                    format!(
+                        "message_id must be 1 to {} ASCII characters without whitespace, got {:?}",
+                        MAX_MESSAGE_ID_LEN, id
+                    ),
+                    "derive the id from something stable, e.g. a hash of the business key",
+                ));
+            }
+        }
+        if let Some(apns_priority) = self.apns_priority {
+            if apns_priority != 5 && apns_priority != 10 {
+                rejections.push((
+                    format!("apns_priority must be 5 or 10, got {}", apns_priority),
+                    "use 10 to deliver right away or 5 to save battery",
+                ));
+            }
+        }
+
+        rejections
+    }
+
+    fn advisories(&self) -> Vec<Advisory> {
+        let mut advisories = Vec::new();
+        if self.content_available == Some(true)
+            && self.notification.is_none()
+            && self.priority != Some(Priority::High)
+        {
+            advisories.push(Advisory::SilentPushNotHighPriority);
+        }
+
+        if let Some(to) = self.to {
+            if !self.to_is_topic()
+                && (to.starts_with("topics/") || to.chars().any(char::is_whitespace))
+            {
+                advisories.push(Advisory::ToLooksLikeTopicName(to.to_string()));
+            }
+        }
+        if self.dry_run == Some(true) && (self.to_is_topic() || self.condition.is_some()) {
+            advisories.push(Advisory::DryRunWithoutTokens);
+        }
+        if self.restricted_package_name.is_some() && self.is_ios_only() {
+            advisories.push(Advisory::RestrictedPackageOnIosOnlyMessage);
+        }
+        if let Some(ref notification) = self.notification {
+            let has_title = !notification.title.is_empty() || notification.title_loc_key.is_some();
+            let has_body = notification.body.is_some() || notification.body_loc_key.is_some();
+            if has_title && !has_body {
+                advisories.push(Advisory::NotificationTitleWithoutBody);
+            } else if has_body && !has_title {
+                advisories.push(Advisory::NotificationBodyWithoutTitle);
+            }
+        }
+
+        advisories
+    }
+
+    // set up for iOS and for nothing Android specific
+    fn is_ios_only(&self) -> bool {
+        let notification = self.notification.as_ref();
+        let for_ios = self.apns_priority.is_some()
+            || self.content_available.is_some()
+            || notification.is_some_and(|n| n.badge.is_some());
+        // data reaches Android apps as well, so does a notification unless it
+        // only carries iOS settings
+        let for_android = self.delay_while_idle.is_some()
+            || self.data.as_ref().is_some_and(|data| !data.is_empty())
+            || notification
+                .is_some_and(|n| n.badge.is_none() || n.tag.is_some() || n.color.is_some());
+
+        for_ios && !for_android
+    }
+}
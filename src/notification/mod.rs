@@ -4,28 +4,31 @@ mod tests;
 /// This struct represents a GCM notification. Use the
 /// corresponding `NotificationBuilder` to get an instance. You can then use
 /// this notification instance when sending a GCM message.
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Notification<'a> {
-    title: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    body: Option<&'a str>,
+    // GCM requires neither, notifications read from JSON may lack both
+    #[serde(default, skip_serializing_if = "str::is_empty")]
+    pub(crate) title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) body: Option<&'a str>,
+    #[serde(default, skip_serializing_if = "str::is_empty")]
     icon: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
     sound: Option<&'a str>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    badge: Option<&'a str>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    tag: Option<&'a str>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    color: Option<&'a str>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) badge: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) color: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
     click_action: Option<&'a str>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    body_loc_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) body_loc_key: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     body_loc_args: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title_loc_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none", borrow)]
+    pub(crate) title_loc_key: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     title_loc_args: Option<Vec<String>>,
 }
@@ -60,7 +63,7 @@ impl<'a> NotificationBuilder<'a> {
     /// Get a new `NotificationBuilder` instance, with a title.
     pub fn new(title: &'a str) -> NotificationBuilder<'a> {
         NotificationBuilder {
-            title: title,
+            title,
             body: None,
             icon: "myicon",
             sound: None,
@@ -0,0 +1,112 @@
+extern crate futures;
+extern crate gcm;
+extern crate serde_json;
+extern crate tokio;
+
+mod support;
+
+use std::time::Duration;
+
+use futures::future;
+use gcm::async_sender::{AsyncGsmSender, TokenBatchFuture, TokenProvider};
+use gcm::limiter::ConcurrencyLimiter;
+use gcm::{Error, Message};
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+struct Pages {
+    pages: Vec<Vec<String>>,
+}
+
+impl TokenProvider for Pages {
+    fn next_batch(&mut self) -> TokenBatchFuture {
+        let page = if self.pages.is_empty() { None } else { Some(self.pages.remove(0)) };
+        Box::new(future::ok(page))
+    }
+}
+
+#[test]
+fn should_share_one_limit_across_cloned_senders() {
+    let gcm = MockGcm::start();
+    gcm.respond(
+        MockResponse::ok(multicast(&[Token::Delivered])).delay(Duration::from_millis(100)),
+    );
+    let limiter = ConcurrencyLimiter::new(2);
+    let sender = AsyncGsmSender::new("key".to_string(), gcm.url(), false)
+        .with_shared_limiter(limiter.clone());
+    let other = AsyncGsmSender::new("key".to_string(), gcm.url(), false)
+        .with_shared_limiter(limiter.clone());
+
+    let senders = [sender.clone(), sender.clone(), sender, other.clone(), other];
+    let sends: Vec<_> = senders
+        .iter()
+        .flat_map(|sender| vec![sender.send(Message::new(vec!["a"])), sender.send(Message::new(vec!["b"]))])
+        .collect();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let responses = runtime.block_on(future::join_all(sends)).unwrap();
+
+    assert_eq!(responses.len(), 10);
+    assert_eq!(gcm.requests().len(), 10);
+    assert_eq!(gcm.max_in_flight(), 2);
+    assert_eq!(limiter.available(), 2);
+}
+
+#[test]
+fn should_send_each_batch_a_provider_yields() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Failed("NotRegistered")])));
+    let sender = AsyncGsmSender::new("key".to_string(), gcm.url(), false);
+    let provider = Pages {
+        pages: vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]],
+    };
+
+    let message = Message::new(vec!["ignored"]).collapse_key("scores");
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let responses = runtime.block_on(sender.send_from_provider(message, provider)).unwrap();
+
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].success, Some(2));
+    assert_eq!(responses[1].failure, Some(1));
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(bodies[0]["registration_ids"], serde_json::json!(["a", "b"]));
+    assert_eq!(bodies[1]["registration_ids"], serde_json::json!(["c"]));
+    assert!(bodies.iter().all(|body| body["collapse_key"] == "scores"));
+}
+
+#[test]
+fn should_surface_the_reason_of_bad_requests() {
+    let gcm = MockGcm::start();
+    gcm.respond(
+        MockResponse::status(400)
+            .body(r#"{"error":"InvalidParameters","error_description":"Invalid JSON: missing registration_ids"}"#),
+    );
+    let sender = AsyncGsmSender::new("key".to_string(), gcm.url(), false);
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(sender.send(Message::new(vec!["a"])));
+
+    assert_eq!(
+        result.err(),
+        Some(Error::InvalidRequest(
+            "InvalidParameters: Invalid JSON: missing registration_ids".to_string()
+        ))
+    );
+}
+
+#[test]
+fn should_map_errors_of_a_single_token_send() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Failed("NotRegistered")])));
+    let sender = AsyncGsmSender::new("key".to_string(), gcm.url(), true);
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let response = runtime.block_on(sender.send(Message::new(vec![]).to("a"))).unwrap();
+
+    let ids_by_error = response.ids_by_error.unwrap();
+    assert_eq!(ids_by_error["NotRegistered"], vec!["a".to_string()]);
+    assert_eq!(gcm.bodies()[0]["to"], "a");
+}
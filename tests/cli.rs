@@ -0,0 +1,61 @@
+#![cfg(feature = "cli")]
+
+extern crate gcm;
+extern crate serde_json;
+
+mod support;
+
+use gcm::cli;
+use gcm::sender::GcmSender;
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+#[test]
+fn should_send_each_line_and_report_each_outcome() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Failed("NotRegistered")])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let input = concat!(
+        r#"{"registration_ids": ["a"], "data": {"text": "café"}}"#,
+        "\n\n",
+        r#"{"to": "b", "priority": "high"}"#,
+        "\n"
+    );
+
+    let mut output = Vec::new();
+    cli::run_send_to_writer(input.as_bytes(), &sender, &mut output).unwrap();
+
+    let outcomes: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(outcomes.len(), 2);
+    assert_eq!(outcomes[0]["line"], 1);
+    assert_eq!(outcomes[0]["status"], "sent");
+    assert_eq!(outcomes[0]["response"]["success"], 1);
+    assert_eq!(outcomes[1]["line"], 3);
+    assert_eq!(outcomes[1]["response"]["results"][0]["error"], "NotRegistered");
+    assert_eq!(gcm.bodies()[0]["data"]["text"], "café");
+    assert_eq!(gcm.bodies()[1]["priority"], "high");
+}
+
+#[test]
+fn should_report_unparsable_lines_and_carry_on() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let input = "not json\n{\"priority\": \"urgent\"}\n{\"registration_ids\": [\"a\"]}\n";
+
+    let mut output = Vec::new();
+    cli::run_send_to_writer(input.as_bytes(), &sender, &mut output).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    let statuses: Vec<String> = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["status"].to_string())
+        .collect();
+    assert_eq!(statuses, vec!["\"invalid\"", "\"invalid\"", "\"sent\""]);
+    assert_eq!(gcm.requests().len(), 1);
+}
@@ -0,0 +1,50 @@
+extern crate gcm;
+
+use gcm::comparison::{Classification, ComparisonSender, Difference};
+use gcm::transport::{SendTransport, TransportResponse};
+use gcm::{Error, Message};
+
+struct CannedTransport(&'static str);
+
+impl SendTransport for CannedTransport {
+    fn post(&self, body: &str) -> Result<TransportResponse, Error> {
+        assert!(body.contains(r#""dry_run":true"#));
+        Ok(TransportResponse {
+            status: 200,
+            retry_after: None,
+            body: self.0.to_string(),
+        })
+    }
+}
+
+#[test]
+fn should_report_recipients_classified_differently() {
+    let legacy = CannedTransport(
+        r#"{"multicast_id":1,"success":2,"failure":1,"canonical_ids":0,"results":[{"message_id":"0:1"},{"error":"NotRegistered"},{"message_id":"0:3"}]}"#,
+    );
+    let v1 = CannedTransport(
+        r#"{"multicast_id":2,"success":1,"failure":2,"canonical_ids":0,"results":[{"message_id":"0:1"},{"error":"NotRegistered"},{"error":"InvalidRegistration"}]}"#,
+    );
+    let sender = ComparisonSender::new(Box::new(legacy), Box::new(v1));
+
+    let comparison = sender.compare(Message::new(vec!["a", "b", "c"]));
+
+    assert!(!comparison.is_equivalent());
+    assert_eq!(
+        comparison.differences,
+        vec![Difference {
+            recipient: "c".to_string(),
+            primary: Classification::Delivered,
+            candidate: Classification::Failed("InvalidRegistration".to_string()),
+        }]
+    );
+    assert_eq!(comparison.candidate.unwrap().multicast_id, Some(2));
+}
+
+#[test]
+fn should_find_identical_responses_equivalent() {
+    let body = r#"{"multicast_id":1,"success":1,"failure":0,"canonical_ids":0,"results":[{"message_id":"0:1"}]}"#;
+    let sender = ComparisonSender::new(Box::new(CannedTransport(body)), Box::new(CannedTransport(body)));
+
+    assert!(sender.compare(Message::new(vec!["a"])).is_equivalent());
+}
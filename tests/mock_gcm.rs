@@ -0,0 +1,82 @@
+//! Example scenarios for the `MockGcm` test server, doubling as checks that the
+//! harness itself behaves.
+extern crate gcm;
+extern crate serde_json;
+
+mod support;
+
+use std::time::{Duration, Instant};
+
+use gcm::sender::GcmSender;
+use gcm::{Error, Message};
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+#[test]
+fn should_serve_scripted_multicast_response() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Canonical(42),
+        Token::Failed("InvalidRegistration"),
+    ])));
+
+    let sender = GcmSender::new(gcm.url(), "secret".to_string());
+    let response = sender.send(Message::new(vec!["a", "b", "c"])).unwrap();
+
+    assert_eq!(response.success, Some(2));
+    assert_eq!(response.failure, Some(1));
+    assert_eq!(response.canonical_ids, Some(1));
+
+    let requests = gcm.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(requests[0].path, "/fcm/send");
+    assert_eq!(requests[0].header("Authorization"), Some("key=secret"));
+    assert_eq!(requests[0].json()["registration_ids"][2], "c");
+}
+
+#[test]
+fn should_serve_scripted_responses_in_order() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(401))
+        .respond(MockResponse::status(500).retry_after(1))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let send = || sender.send(Message::new(vec!["a"]));
+
+    assert_eq!(send().err(), Some(Error::Unauthorized));
+    assert_eq!(send().err(), Some(Error::ServerError));
+    assert!(send().is_ok());
+    // the last scripted response keeps being served
+    assert!(send().is_ok());
+    assert_eq!(gcm.requests().len(), 4);
+}
+
+#[test]
+fn should_delay_scripted_response() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])).delay(Duration::from_millis(200)));
+
+    let started = Instant::now();
+    let result = GcmSender::new(gcm.url(), "key".to_string()).send(Message::new(vec!["a"]));
+
+    assert!(result.is_ok());
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn should_answer_unscripted_requests_with_server_error() {
+    let gcm = MockGcm::start();
+    let result = GcmSender::new(gcm.url(), "key".to_string()).send(Message::new(vec!["a"]));
+
+    assert_eq!(result.err(), Some(Error::ServerError));
+}
+
+#[test]
+fn should_refuse_connections_on_unreachable_url() {
+    let result = GcmSender::new(MockGcm::unreachable_url(), "key".to_string()).send(Message::new(vec!["a"]));
+
+    assert_eq!(result.err(), Some(Error::ServerError));
+}
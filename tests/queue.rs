@@ -0,0 +1,45 @@
+extern crate gcm;
+extern crate serde_json;
+
+mod support;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gcm::async_sender::AsyncGsmSender;
+use gcm::queue::SendQueue;
+use gcm::Message;
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+#[test]
+fn should_send_everything_enqueued_before_shutdown_returns() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(503))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+    let recorded = outcomes.clone();
+    let sender = AsyncGsmSender::new("key".to_string(), gcm.url(), false);
+    let queue = SendQueue::new(sender, 1)
+        .with_retries(1, Duration::from_millis(10))
+        .with_result_handler(Box::new(move |ids, result| {
+            recorded.lock().unwrap().push((ids.len(), result.is_ok()));
+        }));
+
+    let audience: Vec<String> = (0..1500).map(|i| format!("token-{}", i)).collect();
+    queue.enqueue(Message::new(vec![]).collapse_key("a"), vec!["x".to_string()]).unwrap();
+    queue.enqueue(Message::new(vec!["ignored"]).collapse_key("b"), vec!["y".to_string()]).unwrap();
+    queue.enqueue(Message::new(vec![]).collapse_key("c"), audience).unwrap();
+    queue.shutdown();
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 5);
+    assert_eq!(bodies[0], bodies[1]);
+    assert_eq!(bodies[2]["registration_ids"], serde_json::json!(["y"]));
+    assert_eq!(bodies[3]["registration_ids"].as_array().unwrap().len(), 1000);
+    assert_eq!(bodies[4]["registration_ids"].as_array().unwrap().len(), 500);
+    assert_eq!(*outcomes.lock().unwrap(), vec![(1, true), (1, true), (1000, true), (500, true)]);
+}
@@ -0,0 +1,331 @@
+extern crate gcm;
+extern crate serde_json;
+
+mod support;
+
+use std::time::{Duration, Instant};
+
+use gcm::sender::GcmSender;
+use gcm::{Error, Message, Priority};
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+fn sender(gcm: &MockGcm) -> GcmSender {
+    GcmSender::new(gcm.url(), "key".to_string()).with_retries(2, Duration::from_millis(10))
+}
+
+#[test]
+fn should_retry_gateway_errors() {
+    for status in &[502, 503, 504] {
+        let gcm = MockGcm::start();
+        gcm.respond(MockResponse::status(*status))
+            .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+        let outcome = sender(&gcm).send_with_outcome(Message::new(vec!["a"]));
+
+        assert!(outcome.result.is_ok(), "status {}", status);
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(gcm.requests().len(), 2);
+    }
+}
+
+#[test]
+fn should_report_gateway_error_when_retries_run_out() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(502));
+
+    let outcome = sender(&gcm).send_with_outcome(Message::new(vec!["a"]));
+
+    assert_eq!(outcome.result.err(), Some(Error::Gateway { status: 502 }));
+    assert_eq!(outcome.attempts, 3);
+
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(504));
+    let result = GcmSender::new(gcm.url(), "key".to_string()).send(Message::new(vec!["a"]));
+    assert_eq!(result.err(), Some(Error::Gateway { status: 504 }));
+    assert_eq!(gcm.requests().len(), 1);
+}
+
+#[test]
+fn should_honor_retry_after_on_service_unavailable() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(503).retry_after(1))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+    let started = Instant::now();
+    let result = sender(&gcm).send(Message::new(vec!["a"]));
+
+    assert!(result.is_ok());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn should_retry_internal_server_error_but_not_bad_request() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(500))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    assert!(sender(&gcm).send(Message::new(vec!["a"])).is_ok());
+
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(400).body("Missing registration_ids"))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let result = sender(&gcm).send(Message::new(vec!["a"]));
+    assert_eq!(result.err(), Some(Error::InvalidMessage("Missing registration_ids".to_string())));
+    assert_eq!(gcm.requests().len(), 1);
+}
+
+#[test]
+fn should_surface_reason_of_bad_request() {
+    let gcm = MockGcm::start();
+    gcm.respond(
+        MockResponse::status(400)
+            .body(r#"{"error":"InvalidParameters","error_description":"Invalid JSON: missing registration_ids"}"#),
+    )
+    .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+    let result = sender(&gcm).send(Message::new(vec!["a"]));
+
+    assert_eq!(
+        result.err(),
+        Some(Error::InvalidRequest(
+            "InvalidParameters: Invalid JSON: missing registration_ids".to_string()
+        ))
+    );
+    assert_eq!(gcm.requests().len(), 1);
+
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(400).body(
+        r#"{"error":{"code":400,"message":"Request contains an invalid argument.","status":"INVALID_ARGUMENT"}}"#,
+    ));
+    let result = sender(&gcm).send(Message::new(vec!["a"]));
+    assert_eq!(
+        result.err(),
+        Some(Error::InvalidRequest("Request contains an invalid argument.".to_string()))
+    );
+}
+
+#[test]
+fn should_retry_as_a_custom_predicate_decides() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(400).body("proxy hiccup"))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let lenient = sender(&gcm).with_retryable_predicate(Box::new(|error| match *error {
+        Error::InvalidMessage(ref text) => text.contains("hiccup"),
+        _ => false,
+    }));
+
+    let outcome = lenient.send_with_outcome(Message::new(vec!["a"]));
+    assert!(outcome.result.is_ok());
+    assert_eq!(outcome.attempts, 2);
+
+    // the predicate replaces the default, gateway errors are no longer retried
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(503))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let never = sender(&gcm).with_retryable_predicate(Box::new(|_| false));
+    assert_eq!(never.send(Message::new(vec!["a"])).err(), Some(Error::Gateway { status: 503 }));
+    assert_eq!(gcm.requests().len(), 1);
+}
+
+#[test]
+fn should_classify_retryable_errors() {
+    assert!(Error::ServerError.is_retryable());
+    assert!(Error::Gateway { status: 503 }.is_retryable());
+    assert!(!Error::Unauthorized.is_retryable());
+    assert!(!Error::InvalidJsonBody.is_retryable());
+    assert!(!Error::InvalidMessage("bad".to_string()).is_retryable());
+    assert!(!Error::InvalidRequest("bad".to_string()).is_retryable());
+    assert!(!Error::QueueClosed.is_retryable());
+}
+
+#[test]
+fn should_capture_retry_history_with_diagnostics() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(502))
+        .respond(MockResponse::status(503).retry_after(1))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = sender(&gcm).with_diagnostics(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a"]));
+    assert!(outcome.result.is_ok());
+
+    let attempts = outcome.retry_history.unwrap().attempts;
+    assert_eq!(attempts.len(), 3);
+    assert_eq!(attempts[0].status, Some(502));
+    assert_eq!(attempts[0].delay, Duration::from_secs(0));
+    assert_eq!(attempts[0].error, Some(Error::Gateway { status: 502 }));
+    assert_eq!(attempts[1].status, Some(503));
+    assert_eq!(attempts[1].delay, Duration::from_millis(10));
+    assert_eq!(attempts[1].error, Some(Error::Gateway { status: 503 }));
+    assert_eq!(attempts[2].status, Some(200));
+    assert_eq!(attempts[2].delay, Duration::from_secs(1));
+    assert_eq!(attempts[2].error, None);
+}
+
+#[test]
+fn should_not_capture_retry_history_by_default() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(502))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+    let outcome = sender(&gcm).send_with_outcome(Message::new(vec!["a"]));
+
+    assert_eq!(outcome.attempts, 2);
+    assert!(outcome.retry_history.is_none());
+    assert!(outcome.timings.is_none());
+}
+
+#[test]
+fn should_time_serialization_and_network_with_diagnostics() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(502).delay(Duration::from_millis(30)))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])).delay(Duration::from_millis(30)));
+    let sender = sender(&gcm).with_diagnostics(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a"]));
+    let timings = outcome.timings.unwrap();
+
+    assert!(timings.network >= Duration::from_millis(60), "{:?}", timings);
+    assert!(timings.serialization < timings.network);
+    // the wait before the retry counts towards the latency only
+    assert!(timings.serialization + timings.network + Duration::from_millis(10) <= outcome.latency);
+}
+
+#[test]
+fn should_not_resend_to_confirmed_tokens_after_a_cut_response() {
+    let gcm = MockGcm::start();
+    let first = multicast(&[Token::Delivered, Token::Delivered, Token::Delivered]);
+    // the connection drops right after the second result
+    let cut = first.match_indices("message_id").nth(2).unwrap().0;
+    gcm.respond(MockResponse::ok(first.clone()).cut_after(cut))
+        .respond(MockResponse::ok(multicast(&[Token::Canonical(9)])));
+
+    let response = sender(&gcm).send_idempotent(Message::new(vec!["a", "b", "c"])).unwrap();
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(bodies[0]["registration_ids"], serde_json::json!(["a", "b", "c"]));
+    assert_eq!(bodies[1]["registration_ids"], serde_json::json!(["c"]));
+
+    let results = response.results.unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].message_id, Some("0:0".to_string()));
+    assert_eq!(results[1].message_id, Some("0:1".to_string()));
+    assert_eq!(results[2].registration_id, Some(9));
+    assert_eq!((response.success, response.failure, response.canonical_ids), (Some(3), Some(0), Some(1)));
+}
+
+#[test]
+fn should_not_resend_to_refused_tokens_after_a_cut_response() {
+    let gcm = MockGcm::start();
+    let first = multicast(&[Token::Delivered, Token::Failed("NotRegistered"), Token::Delivered]);
+    // the connection drops right after the refusal of "b"
+    let cut = first.match_indices("message_id").nth(1).unwrap().0;
+    gcm.respond(MockResponse::ok(first.clone()).cut_after(cut))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+
+    let response = sender(&gcm).send_idempotent(Message::new(vec!["a", "b", "c"])).unwrap();
+
+    assert_eq!(gcm.bodies()[1]["registration_ids"], serde_json::json!(["c"]));
+    let results = response.results.unwrap();
+    assert_eq!(results[1].error, Some("NotRegistered".to_string()));
+    assert!(results[2].message_id.is_some());
+}
+
+#[test]
+fn should_resend_everything_without_confirmations() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::status(503))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("NotRegistered")])));
+
+    let response = sender(&gcm).send_idempotent(Message::new(vec!["a", "b"])).unwrap();
+
+    assert_eq!(gcm.bodies()[1]["registration_ids"], serde_json::json!(["a", "b"]));
+    assert_eq!(response.failure_count(), 1);
+    assert_eq!(response.results.unwrap()[1].error, Some("NotRegistered".to_string()));
+}
+
+#[test]
+fn should_retry_rate_limited_tokens_at_normal_priority() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Failed("DeviceMessageRateExceeded"),
+    ])))
+    .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = sender(&gcm).with_rate_limit_downgrade(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a", "b"]).priority(Priority::High));
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(bodies[0]["priority"], "high");
+    assert_eq!(bodies[1]["priority"], "normal");
+    assert_eq!(bodies[1]["registration_ids"], serde_json::json!(["b"]));
+    assert_eq!(outcome.attempts, 2);
+
+    let response = outcome.result.unwrap();
+    assert_eq!((response.success, response.failure), (Some(2), Some(0)));
+    assert!(response.results.unwrap()[1].error.is_none());
+}
+
+#[test]
+fn should_share_retry_budget_between_send_and_downgrade() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])))
+        .respond(MockResponse::status(503))
+        .respond(MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string())
+        .with_retries(1, Duration::from_millis(10))
+        .with_rate_limit_downgrade(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a"]).priority(Priority::High));
+
+    // the downgraded send, then its one retry; the budget is used up after that
+    assert_eq!(gcm.bodies().len(), 3);
+    assert_eq!(outcome.attempts, 3);
+    assert_eq!(outcome.result.unwrap().failure, Some(1));
+}
+
+#[test]
+fn should_downgrade_once_without_retries() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])))
+        .respond(MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string()).with_rate_limit_downgrade(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a"]).priority(Priority::High));
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(bodies[1]["priority"], "normal");
+    assert_eq!(outcome.attempts, 2);
+}
+
+#[test]
+fn should_time_downgraded_sends_with_diagnostics() {
+    let gcm = MockGcm::start();
+    gcm.respond(
+        MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])).delay(Duration::from_millis(30)),
+    )
+    .respond(MockResponse::ok(multicast(&[Token::Delivered])).delay(Duration::from_millis(30)));
+    let sender = sender(&gcm).with_rate_limit_downgrade(true).with_diagnostics(true);
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["a"]).priority(Priority::High));
+
+    assert_eq!(outcome.attempts, 2);
+    let timings = outcome.timings.unwrap();
+    assert!(timings.network >= Duration::from_millis(60), "{:?}", timings);
+}
+
+#[test]
+fn should_keep_rate_limit_errors_without_downgrade() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Failed("DeviceMessageRateExceeded")])));
+
+    let response = sender(&gcm).send(Message::new(vec!["a"]).priority(Priority::High)).unwrap();
+
+    assert_eq!(gcm.requests().len(), 1);
+    assert_eq!(response.failure, Some(1));
+}
@@ -0,0 +1,393 @@
+extern crate gcm;
+extern crate serde_json;
+
+mod support;
+
+use gcm::sender::{GcmSender, KeyCase};
+use gcm::{gcm_util, Error, ErrorReason, Message, Priority};
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+#[test]
+fn should_apply_body_transform_before_sending() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string()).with_body_transform(Box::new(|mut body| {
+        body["audit_id"] = "abc-123".into();
+        body
+    }));
+
+    let result = sender.send(Message::new(vec!["token"]).dry_run(true));
+    assert!(result.is_ok());
+
+    let body = &gcm.bodies()[0];
+    assert_eq!(body["audit_id"], "abc-123");
+    assert_eq!(body["registration_ids"][0], "token");
+    assert_eq!(body["dry_run"], true);
+}
+
+#[test]
+fn should_call_back_once_per_token() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Failed("NotRegistered"),
+        Token::Delivered,
+    ])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    let mut seen = Vec::new();
+    let response = sender
+        .send_with_callback_per_token(Message::new(vec!["a", "b", "c"]), |token, result| {
+            seen.push((token.to_string(), result.error.clone()));
+        })
+        .unwrap();
+
+    assert_eq!(response.success, Some(2));
+    assert_eq!(
+        seen,
+        vec![
+            ("a".to_string(), None),
+            ("b".to_string(), Some("NotRegistered".to_string())),
+            ("c".to_string(), None),
+        ]
+    );
+}
+
+#[test]
+fn should_count_successes_and_failures() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Failed("NotRegistered"),
+        Token::Canonical(7),
+        Token::Failed("Unavailable"),
+        Token::Failed("InvalidRegistration"),
+    ])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    let counts = sender.send_count(Message::new(vec!["a", "b", "c", "d", "e"]));
+
+    assert_eq!(counts, Ok((2, 3)));
+}
+
+#[test]
+fn should_share_operation_id_across_split_sends() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    let messages = Message::new(vec!["a", "vip", "b"])
+        .split_by(|token| token == "vip")
+        .into_iter()
+        .map(|(vip, message)| message.priority(if vip { Priority::High } else { Priority::Normal }))
+        .collect();
+    let operation_id = gcm_util::new_operation_id();
+    let outcomes = sender.send_operation(&operation_id, messages);
+
+    assert_eq!(outcomes.len(), 2);
+    assert!(outcomes.iter().all(|o| o.correlation.operation_id == operation_id));
+    assert_eq!(outcomes[0].correlation.index, 0);
+    assert_eq!(outcomes[1].correlation.index, 1);
+    assert!(outcomes.iter().all(|o| o.result.is_ok()));
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies[0]["priority"], "normal");
+    assert_eq!(bodies[0]["registration_ids"], serde_json::json!(["a", "b"]));
+    assert_eq!(bodies[1]["priority"], "high");
+    assert_eq!(bodies[1]["registration_ids"], serde_json::json!(["vip"]));
+
+    assert_ne!(sender.send_with_outcome(Message::new(vec!["a"])).correlation.operation_id, operation_id);
+}
+
+#[test]
+fn should_camel_case_top_level_keys_when_enabled() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string()).with_key_case(KeyCase::Camel);
+
+    let mut data = ::std::collections::HashMap::new();
+    data.insert("order_id", "1");
+    let message = Message::new(vec!["a"])
+        .time_to_live(60)
+        .restricted_package_name("com.example")
+        .data(data);
+    assert!(sender.send(message).is_ok());
+
+    assert_eq!(
+        gcm.bodies()[0],
+        serde_json::json!({
+            "registrationIds": ["a"],
+            "timeToLive": 60,
+            "restrictedPackageName": "com.example",
+            "data": {"order_id": "1"}
+        })
+    );
+}
+
+#[test]
+fn should_keep_snake_case_keys_by_default() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    assert!(sender.send(Message::new(vec!["a"]).time_to_live(60)).is_ok());
+
+    assert_eq!(
+        gcm.bodies()[0],
+        serde_json::json!({"registration_ids": ["a"], "time_to_live": 60})
+    );
+}
+
+#[test]
+fn should_dry_run_a_seeded_sample() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Failed("NotRegistered"),
+        Token::Delivered,
+    ])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let audience = vec!["t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", "t8", "t9"];
+
+    let summary = sender
+        .dry_run_sample_seeded(Message::new(audience.clone()), 0.3, 42)
+        .unwrap();
+
+    assert_eq!(summary.sampled, 3);
+    assert_eq!(summary.success, 2);
+    assert_eq!(summary.failure, 1);
+    assert_eq!(summary.errors.get("NotRegistered"), Some(&1));
+
+    let body = &gcm.bodies()[0];
+    assert_eq!(body["dry_run"], true);
+    let sampled = body["registration_ids"].as_array().unwrap().clone();
+    assert_eq!(sampled.len(), 3);
+    assert!(sampled.iter().all(|id| audience.contains(&id.as_str().unwrap())));
+
+    // the same seed picks the same sample
+    sender
+        .dry_run_sample_seeded(Message::new(audience.clone()), 0.3, 42)
+        .unwrap();
+    assert_eq!(gcm.bodies()[1]["registration_ids"], serde_json::Value::Array(sampled));
+}
+
+#[test]
+fn should_connect_ahead_of_sending_on_warm_up() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    assert!(sender.warm_up().is_ok());
+    assert_eq!(gcm.connections(), 1);
+    assert_eq!(gcm.requests()[0].method, "HEAD");
+
+    assert!(sender.send(Message::new(vec!["a"])).is_ok());
+    assert_eq!(gcm.connections(), 1);
+    assert_eq!(gcm.bodies().len(), 1);
+}
+
+#[test]
+fn should_connect_for_every_send_without_warm_up() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    assert!(sender.send(Message::new(vec!["a"])).is_ok());
+    assert!(sender.send(Message::new(vec!["b"])).is_ok());
+    assert_eq!(gcm.connections(), 2);
+}
+
+#[test]
+fn should_apply_default_priority_to_topic_messages_only() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(r#"{"message_id":7}"#));
+    let sender = GcmSender::new(gcm.url(), "key".to_string()).with_default_topic_priority(Priority::High);
+
+    assert!(sender.send(Message::new(vec![]).to("/topics/news")).is_ok());
+    assert!(sender.send(Message::new(vec![]).condition("'news' in topics")).is_ok());
+    assert!(sender.send(Message::new(vec![]).to("/topics/news").priority(Priority::Normal)).is_ok());
+    assert!(sender.send(Message::new(vec!["a"])).is_ok());
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies[0]["priority"], "high");
+    assert_eq!(bodies[1]["priority"], "high");
+    assert_eq!(bodies[2]["priority"], "normal");
+    assert!(bodies[3].get("priority").is_none());
+}
+
+#[test]
+fn should_export_outcome_as_redacted_event() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("NotRegistered")])))
+        .respond(MockResponse::status(400).body("bad request for secret-token-2"));
+    let sender = GcmSender::new(gcm.url(), "secret-key".to_string());
+
+    let outcome = sender.send_with_outcome(Message::new(vec!["secret-token-1", "secret-token-2"]));
+    let event = outcome.to_event();
+
+    assert_eq!(event["operation_id"], outcome.correlation.operation_id.as_str());
+    assert_eq!(event["status"], "ok");
+    assert_eq!(event["multicast_id"], 108);
+    assert_eq!(event["success"], 1);
+    assert_eq!(event["failure"], 1);
+    assert_eq!(event["attempts"], 1);
+    assert!(event["latency_ms"].is_u64());
+    assert!(event.as_object().unwrap().values().all(|value| !value.is_object() && !value.is_array()));
+
+    let failed = sender.send_with_outcome(Message::new(vec!["secret-token-2"])).to_event();
+    assert_eq!(failed["status"], "error");
+    assert_eq!(failed["error"], "InvalidMessage");
+
+    for event in &[event, failed] {
+        assert!(!event.to_string().contains("secret"), "leaked in {}", event);
+    }
+}
+
+#[test]
+fn should_close_connections_on_close() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    sender.warm_up().unwrap();
+
+    assert!(sender.send(Message::new(vec!["a"])).is_ok());
+    assert_eq!(gcm.closed_connections(), 0);
+
+    sender.close();
+    assert!(gcm.wait_for_closed(1));
+}
+
+#[test]
+fn should_group_tokens_sharing_personalized_data() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Delivered])))
+        .respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let mut base_data = ::std::collections::HashMap::new();
+    base_data.insert("campaign", "spring");
+    base_data.insert("greeting", "Hi");
+    let base = Message::new(vec![]).collapse_key("spring").data(base_data);
+    let ids = vec!["ann-1".to_string(), "bob-1".to_string(), "ann-2".to_string()];
+
+    let outcomes = sender.send_personalized(base, ids, |token| {
+        let mut data = serde_json::Map::new();
+        let name = if token.starts_with("ann") { "Ann" } else { "Bob" };
+        data.insert("greeting".to_string(), format!("Hi {}", name).into());
+        data.insert("visits".to_string(), 3.into());
+        data
+    });
+
+    assert_eq!(outcomes.len(), 2);
+    assert!(outcomes.iter().all(|o| o.result.is_ok()));
+    assert_eq!(outcomes[0].correlation.operation_id, outcomes[1].correlation.operation_id);
+
+    let bodies = gcm.bodies();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(bodies[0]["registration_ids"], serde_json::json!(["ann-1", "ann-2"]));
+    assert_eq!(
+        bodies[0]["data"],
+        serde_json::json!({"campaign": "spring", "greeting": "Hi Ann", "visits": "3"})
+    );
+    assert_eq!(bodies[0]["collapse_key"], "spring");
+    assert_eq!(bodies[1]["registration_ids"], serde_json::json!(["bob-1"]));
+    assert_eq!(bodies[1]["data"]["greeting"], "Hi Bob");
+}
+
+#[test]
+fn should_flag_duplicate_sends_within_window() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string()).with_duplicate_detection(2);
+
+    let first = sender.send_with_outcome(Message::new(vec!["a"]));
+    let again = sender.send_with_outcome(Message::new(vec!["a"]));
+    let other = sender.send_with_outcome(Message::new(vec!["b"]));
+
+    assert!(!first.duplicate);
+    assert!(again.duplicate);
+    assert!(!other.duplicate);
+    assert_eq!(first.payload_hash, again.payload_hash);
+    assert_ne!(first.payload_hash, other.payload_hash);
+    assert_eq!(again.to_event()["duplicate"], true);
+    assert_eq!(gcm.bodies().len(), 3);
+
+    // "a" has left the window of two by now
+    sender.send_with_outcome(Message::new(vec!["c"]));
+    assert!(!sender.send_with_outcome(Message::new(vec!["a"])).duplicate);
+
+    let plain = GcmSender::new(gcm.url(), "key".to_string()).send_with_outcome(Message::new(vec!["a"]));
+    assert_eq!(plain.payload_hash, None);
+    assert!(plain.to_event().get("duplicate").is_none());
+}
+
+#[test]
+fn should_fail_strict_send_on_permanent_failures() {
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[
+        Token::Delivered,
+        Token::Failed("NotRegistered"),
+        Token::Failed("Unavailable"),
+    ])))
+    .respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("Unavailable")])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+    let ids = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+    let result = sender.send_strict(Message::new(vec![]), ids);
+    assert_eq!(
+        result.err(),
+        Some(Error::PermanentFailures(vec![("b".to_string(), ErrorReason::NotRegistered)]))
+    );
+
+    let response = sender
+        .send_strict(Message::new(vec![]), vec!["a".to_string(), "c".to_string()])
+        .unwrap();
+    assert_eq!(response.failure, Some(1));
+    assert_eq!(gcm.bodies()[1]["registration_ids"], serde_json::json!(["a", "c"]));
+}
+
+#[test]
+fn should_fail_over_to_fallback_endpoint_when_primary_is_down() {
+    let fallback = MockGcm::start();
+    fallback.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new(MockGcm::unreachable_url(), "key".to_string()).with_fallback_endpoint(fallback.url());
+
+    let response = sender.send(Message::new(vec!["a"])).unwrap();
+    assert_eq!(response.success, Some(1));
+    assert_eq!(fallback.requests()[0].header("Authorization"), Some("key=key"));
+}
+
+#[test]
+fn should_fail_over_when_primary_host_does_not_resolve() {
+    let fallback = MockGcm::start();
+    fallback.respond(MockResponse::ok(multicast(&[Token::Delivered])));
+    let sender = GcmSender::new("http://gcm-primary.invalid/fcm/send".to_string(), "key".to_string())
+        .with_fallback_endpoint(fallback.url());
+
+    assert!(sender.send(Message::new(vec!["a"])).is_ok());
+    assert_eq!(fallback.bodies().len(), 1);
+}
+
+#[test]
+fn should_not_fail_over_on_answered_requests() {
+    let primary = MockGcm::start();
+    let fallback = MockGcm::start();
+    primary.respond(MockResponse::status(401));
+    let sender = GcmSender::new(primary.url(), "key".to_string()).with_fallback_endpoint(fallback.url());
+
+    assert_eq!(sender.send(Message::new(vec!["a"])).err(), Some(Error::Unauthorized));
+    assert!(fallback.requests().is_empty());
+}
+
+#[test]
+fn should_not_fail_over_when_connection_drops_after_sending() {
+    let primary = MockGcm::start();
+    let fallback = MockGcm::start();
+    primary.respond(MockResponse::status(200).hang_up());
+    let sender = GcmSender::new(primary.url(), "key".to_string()).with_fallback_endpoint(fallback.url());
+
+    assert!(sender.send(Message::new(vec!["a"])).is_err());
+    assert_eq!(primary.bodies().len(), 1);
+    assert!(fallback.requests().is_empty());
+}
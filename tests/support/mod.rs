@@ -0,0 +1,377 @@
+//! A scriptable stand-in for the GCM endpoint, shared by the integration tests.
+//!
+//! Start a server, script the answers it should give in order, point a sender at
+//! `url()` and inspect what arrived afterwards:
+//!
+//! ```rust,ignore
+//! mod support;
+//! use support::{multicast, MockGcm, MockResponse, Token};
+//!
+//! let gcm = MockGcm::start();
+//! gcm.respond(MockResponse::status(503).retry_after(1));
+//! gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("NotRegistered")])));
+//!
+//! let sender = GcmSender::new(gcm.url(), "key".to_string());
+//! // ... send ...
+//! assert_eq!(gcm.requests().len(), 2);
+//! ```
+//!
+//! Scripted responses are used up one per request; the last one keeps being
+//! served once the others are gone. Without any script every request gets a 500.
+//! `HEAD` requests are recorded but do not use up the script, they get an empty 200.
+//! Connections are kept alive, so `connections()` tells how many TCP connections
+//! the client opened, and `max_in_flight()` how many requests were served at once.
+#![allow(dead_code)]
+
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::serde_json::Value;
+
+/// The result GCM reports for a single registration id in a multicast response.
+#[derive(Clone, Copy)]
+pub enum Token {
+    Delivered,
+    Canonical(u64),
+    Failed(&'static str),
+}
+
+/// Build a multicast response body with one result per entry of `tokens`.
+pub fn multicast(tokens: &[Token]) -> String {
+    let mut success = 0;
+    let mut canonical = 0;
+    let results: Vec<Value> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| match *token {
+            Token::Delivered => {
+                success += 1;
+                json_object(&[("message_id", Value::from(format!("0:{}", i)))])
+            }
+            Token::Canonical(id) => {
+                success += 1;
+                canonical += 1;
+                json_object(&[
+                    ("message_id", Value::from(format!("0:{}", i))),
+                    ("registration_id", Value::from(id)),
+                ])
+            }
+            Token::Failed(error) => json_object(&[("error", Value::from(error))]),
+        })
+        .collect();
+
+    json_object(&[
+        ("multicast_id", Value::from(108)),
+        ("success", Value::from(success)),
+        ("failure", Value::from(tokens.len() - success)),
+        ("canonical_ids", Value::from(canonical)),
+        ("results", Value::from(results)),
+    ])
+    .to_string()
+}
+
+fn json_object(fields: &[(&str, Value)]) -> Value {
+    Value::Object(
+        fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+    )
+}
+
+/// One scripted answer of the mock server.
+#[derive(Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Option<Duration>,
+    cut_after: Option<usize>,
+    hang_up: bool,
+}
+
+impl MockResponse {
+    pub fn ok<S: Into<String>>(body: S) -> MockResponse {
+        MockResponse::status(200).body(body)
+    }
+
+    pub fn status(status: u16) -> MockResponse {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+            delay: None,
+            cut_after: None,
+            hang_up: false,
+        }
+    }
+
+    pub fn body<S: Into<String>>(mut self, body: S) -> MockResponse {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn retry_after(self, seconds: u64) -> MockResponse {
+        self.header("Retry-After", &seconds.to_string())
+    }
+
+    /// Wait this long after reading the request before answering.
+    pub fn delay(mut self, delay: Duration) -> MockResponse {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Drop the connection after sending the first `bytes` of the body, as a
+    /// read timeout would look to the client. The full length is announced.
+    pub fn cut_after(mut self, bytes: usize) -> MockResponse {
+        self.cut_after = Some(bytes);
+        self
+    }
+
+    /// Drop the connection after reading the request, without answering.
+    pub fn hang_up(mut self) -> MockResponse {
+        self.hang_up = true;
+        self
+    }
+}
+
+/// A request as received by the mock server.
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).expect("request body is not JSON")
+    }
+}
+
+#[derive(Default)]
+struct State {
+    script: VecDeque<MockResponse>,
+    requests: Vec<RecordedRequest>,
+    connections: usize,
+    closed: usize,
+    in_flight: usize,
+    max_in_flight: usize,
+}
+
+pub struct MockGcm {
+    url: String,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+    address: String,
+}
+
+impl MockGcm {
+    pub fn start() -> MockGcm {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let accept_state = state.clone();
+        let accept_stopped = stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    accept_state.lock().unwrap().connections += 1;
+                    let state = accept_state.clone();
+                    thread::spawn(move || serve(stream, state));
+                }
+            }
+        });
+
+        MockGcm {
+            url: format!("http://{}/fcm/send", address),
+            state,
+            stopped,
+            address,
+        }
+    }
+
+    /// A URL nothing listens on, connecting to it is refused.
+    pub fn unreachable_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/fcm/send", listener.local_addr().unwrap())
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Queue the answer for the next unanswered request.
+    pub fn respond(&self, response: MockResponse) -> &MockGcm {
+        self.state.lock().unwrap().script.push_back(response);
+        self
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The JSON bodies of the `POST` requests received.
+    pub fn bodies(&self) -> Vec<Value> {
+        self.requests()
+            .iter()
+            .filter(|request| request.method == "POST")
+            .map(RecordedRequest::json)
+            .collect()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// How many of the `connections()` have been closed since, by either side.
+    pub fn closed_connections(&self) -> usize {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Wait up to a second for `closed_connections()` to reach `count`.
+    pub fn wait_for_closed(&self, count: usize) -> bool {
+        for _ in 0..100 {
+            if self.closed_connections() >= count {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.state.lock().unwrap().max_in_flight
+    }
+}
+
+impl Drop for MockGcm {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it notices
+        let _ = TcpStream::connect(&self.address);
+    }
+}
+
+fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    while let Some(request) = read_request(&mut reader) {
+        let head_only = request.method == "HEAD";
+        let response = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(request);
+            state.in_flight += 1;
+            state.max_in_flight = state.max_in_flight.max(state.in_flight);
+            if head_only {
+                Some(MockResponse::status(200))
+            } else if state.script.len() > 1 {
+                state.script.pop_front()
+            } else {
+                state.script.front().cloned()
+            }
+        };
+        let response = response
+            .unwrap_or_else(|| MockResponse::status(500).body("unscripted request"));
+
+        if let Some(delay) = response.delay {
+            thread::sleep(delay);
+        }
+        state.lock().unwrap().in_flight -= 1;
+        if response.hang_up {
+            break;
+        }
+
+        let mut head = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n",
+            response.status,
+            response.body.len()
+        );
+        if response.status == 200 {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        let body = match response.cut_after {
+            Some(bytes) => &response.body.as_bytes()[..bytes],
+            None => response.body.as_bytes(),
+        };
+        if writer.write_all(head.as_bytes()).is_err()
+            || (!head_only && writer.write_all(body).is_err())
+            || response.cut_after.is_some()
+        {
+            break;
+        }
+    }
+
+    let _ = writer.shutdown(Shutdown::Both);
+    state.lock().unwrap().closed += 1;
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<RecordedRequest> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next()?.trim().to_string();
+        let value = header.next().unwrap_or("").trim().to_string();
+        headers.push((name, value));
+    }
+
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(RecordedRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8(body).ok()?,
+    })
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "opentelemetry")]
+
+extern crate gcm;
+extern crate opentelemetry;
+extern crate opentelemetry_sdk;
+extern crate serde_json;
+extern crate tokio;
+
+mod support;
+
+use std::sync::Mutex;
+
+use opentelemetry::trace::Status;
+use opentelemetry::{global, KeyValue, Value};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+use gcm::async_sender::AsyncGsmSender;
+use gcm::sender::GcmSender;
+use gcm::Message;
+
+use support::{multicast, MockGcm, MockResponse, Token};
+
+// the tracer provider is global, tests installing theirs take turns
+static TRACING: Mutex<()> = Mutex::new(());
+
+fn install_exporter() -> InMemorySpanExporter {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    global::set_tracer_provider(provider);
+    exporter
+}
+
+fn attribute(attributes: &[KeyValue], key: &str) -> Option<Value> {
+    attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| kv.value.clone())
+}
+
+#[test]
+fn should_record_span_per_send() {
+    let _tracing = TRACING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let exporter = install_exporter();
+
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("NotRegistered")])))
+        .respond(MockResponse::status(401))
+        .respond(MockResponse::status(400).body("bad registration id secret-token-4"));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    assert!(sender.send(Message::new(vec!["secret-token-1", "secret-token-2"])).is_ok());
+    assert!(sender.send(Message::new(vec!["secret-token-3"])).is_err());
+    assert!(sender.send(Message::new(vec!["secret-token-4"])).is_err());
+
+    let spans = exporter.get_finished_spans().unwrap();
+    assert_eq!(spans.len(), 3);
+
+    let ok = &spans[0];
+    assert_eq!(ok.name, "send registration_ids");
+    assert_eq!(ok.status, Status::Ok);
+    assert_eq!(attribute(&ok.attributes, "messaging.system"), Some(Value::from("gcm")));
+    assert_eq!(
+        attribute(&ok.attributes, "messaging.destination.name"),
+        Some(Value::from("registration_ids"))
+    );
+    assert_eq!(attribute(&ok.attributes, "messaging.batch.message_count"), Some(Value::from(2)));
+    assert_eq!(attribute(&ok.attributes, "messaging.message.id"), Some(Value::from("108")));
+    assert_eq!(attribute(&ok.attributes, "gcm.success"), Some(Value::from(1)));
+    assert_eq!(attribute(&ok.attributes, "gcm.failure"), Some(Value::from(1)));
+
+    let failed = &spans[1];
+    assert_eq!(failed.status, Status::error("Unauthorized"));
+    assert_eq!(attribute(&failed.attributes, "error.type"), Some(Value::from("Unauthorized")));
+
+    let rejected = &spans[2];
+    assert_eq!(rejected.status, Status::error("InvalidMessage"));
+    assert_eq!(attribute(&rejected.attributes, "error.type"), Some(Value::from("InvalidMessage")));
+
+    for span in &spans {
+        for kv in span.attributes.iter() {
+            assert!(!kv.value.as_str().contains("secret-token"));
+        }
+        assert!(!format!("{:?}", span.status).contains("secret-token"));
+    }
+}
+
+#[test]
+fn should_record_span_for_every_way_of_sending() {
+    let _tracing = TRACING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let exporter = install_exporter();
+
+    let gcm = MockGcm::start();
+    gcm.respond(MockResponse::ok(multicast(&[Token::Delivered, Token::Failed("NotRegistered")])));
+    let sender = GcmSender::new(gcm.url(), "key".to_string());
+
+    assert_eq!(sender.send_count(Message::new(vec!["a", "b"])).unwrap(), (1, 1));
+    assert!(sender.send_idempotent(Message::new(vec!["a", "b"])).is_ok());
+
+    let async_sender = AsyncGsmSender::new("key".to_string(), gcm.url(), false);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert!(runtime.block_on(async_sender.send(Message::new(vec![]).to("a"))).is_ok());
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name.to_string()).collect();
+    assert_eq!(names, vec!["send registration_ids", "send registration_ids", "send token"]);
+    for span in &spans {
+        assert_eq!(attribute(&span.attributes, "gcm.failure"), Some(Value::from(1)));
+    }
+}
@@ -0,0 +1,64 @@
+extern crate gcm;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use gcm::sender::GcmSender;
+use gcm::transport::{
+    request_key, RecordingTransport, ReplayTransport, SendTransport, TransportResponse,
+};
+use gcm::{Error, Message};
+
+const OK_BODY: &str = r#"{"multicast_id":7,"success":1,"failure":1,"canonical_ids":0,"results":[{"message_id":"0:1"},{"error":"NotRegistered"}]}"#;
+
+struct CannedTransport {
+    calls: Arc<AtomicUsize>,
+}
+
+impl SendTransport for CannedTransport {
+    fn post(&self, _body: &str) -> Result<TransportResponse, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(TransportResponse {
+            status: 200,
+            retry_after: None,
+            body: OK_BODY.to_string(),
+        })
+    }
+}
+
+#[test]
+fn should_replay_recorded_send() {
+    let path = env::temp_dir().join(format!("gcm-recording-{}.jsonl", process::id()));
+    let _ = fs::remove_file(&path);
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let canned = CannedTransport {
+        calls: calls.clone(),
+    };
+    let recorder = GcmSender::with_transport(Box::new(RecordingTransport::new(canned, &path).unwrap()));
+    let recorded = recorder.send(Message::new(vec!["a", "b"]).collapse_key("scores")).unwrap();
+
+    let replayer = GcmSender::with_transport(Box::new(ReplayTransport::from_file(&path).unwrap()));
+    let replayed = replayer.send(Message::new(vec!["a", "b"]).collapse_key("scores")).unwrap();
+    let unknown = replayer.send(Message::new(vec!["a", "b"]).collapse_key("news"));
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(replayed.multicast_id, recorded.multicast_id);
+    assert_eq!(replayed.failure, Some(1));
+    assert_eq!(replayed.results.unwrap()[1].error, Some("NotRegistered".to_string()));
+    assert!(unknown.is_err());
+}
+
+#[test]
+fn should_key_requests_by_target_and_payload() {
+    let key = request_key(r#"{"registration_ids":["a","b"],"dry_run":true}"#);
+
+    assert!(key.starts_with("a,b#"));
+    assert_eq!(key, request_key(r#"{"dry_run":true,"registration_ids":["a","b"]}"#));
+    assert_ne!(key, request_key(r#"{"registration_ids":["a","b"],"dry_run":false}"#));
+    assert_ne!(key, request_key(r#"{"registration_ids":["a"],"dry_run":true}"#));
+}